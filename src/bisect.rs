@@ -0,0 +1,202 @@
+use crate::config::Config;
+use crate::metrics::{self, RunRecord};
+use crate::runner::{self, RunOptions};
+use crate::workspace;
+use anyhow::{anyhow, Context, Result};
+use std::path::PathBuf;
+
+/// Run recorded for every bisect step
+const TRIGGER: &str = "bisect";
+
+/// What to bisect and how each candidate is checked.
+pub struct BisectArgs<'a> {
+    pub good: &'a str,
+    pub bad: &'a str,
+    pub mode: &'a str,
+    /// Judge commits by this stage alone instead of the whole pipeline
+    pub stage: Option<&'a str>,
+    pub save: bool,
+}
+
+/// Find the first commit between `good` and `bad` (first-parent history)
+/// where the pipeline, or the chosen stage, fails. Candidates are checked
+/// out in a scratch worktree; the project's target directory is shared.
+pub fn bisect(cfg: &Config, args: &BisectArgs, opts: RunOptions) -> Result<()> {
+    let good = resolve(args.good)?;
+    let bad = resolve(args.bad)?;
+    let range = format!("{}..{}", good, bad);
+    let mut commits: Vec<String> = workspace::git(&["rev-list", "--first-parent", &range])?
+        .lines()
+        .map(|l| l.trim().to_string())
+        .collect();
+    commits.reverse();
+    if commits.is_empty() {
+        return Err(anyhow!(
+            "no commits between `{}` and `{}` (is good an ancestor of bad?)",
+            args.good,
+            args.bad
+        ));
+    }
+
+    let target_dir = workspace::target_dir().ok();
+    let tree = Worktree::add(&bad)?;
+    let prefix = workspace::git(&["rev-parse", "--show-prefix"])?;
+    let workdir = tree.dir.join(prefix.trim());
+    let target = match args.stage {
+        Some(st) => format!("stage `{}`", st),
+        None => format!("mode `{}`", args.mode),
+    };
+    println!(
+        "Bisecting {} over {} commit(s) ({} runs at most)",
+        target,
+        commits.len(),
+        steps_needed(commits.len()) + 1
+    );
+
+    let mut runs: Vec<Option<RunRecord>> = commits.iter().map(|_| None).collect();
+    let mut check = |i: usize| -> Result<bool> {
+        let commit = &commits[i];
+        tree.checkout(commit)?;
+        let run_opts = RunOptions {
+            workdir: Some(workdir.clone()),
+            target_dir: target_dir.clone(),
+            commit: Some(commit.clone()),
+            only: args.stage.map(str::to_string),
+            trigger: Some(TRIGGER.to_string()),
+            ..opts.clone()
+        };
+        let run = runner::run_pipeline(cfg, args.mode, &run_opts)?;
+        if args.save {
+            metrics::append_run(&run)?;
+        }
+        if run.aborted {
+            return Err(anyhow!("bisect interrupted"));
+        }
+        let failed = is_bad(&run, args.stage);
+        println!(
+            "  {}  {}  {} ms  {}",
+            short(commit),
+            if failed { "bad " } else { "good" },
+            run.total_ms,
+            subject(commit)
+        );
+        runs[i] = Some(run);
+        Ok(failed)
+    };
+
+    // The search trusts `bad`; make sure it really fails
+    let last = commits.len() - 1;
+    if !check(last)? {
+        return Err(anyhow!(
+            "`{}` passes, so there is nothing to bisect",
+            args.bad
+        ));
+    }
+    let first = first_bad(last, &mut check)?;
+
+    let commit = &commits[first];
+    println!();
+    println!("First bad commit: {} {}", commit, subject(commit));
+    if let Some(run) = &runs[first] {
+        runner::print_run_summary(run, false);
+    }
+    Ok(())
+}
+
+/// Index of the first bad commit, given that `last` is bad and everything
+/// before index 0 is good.
+fn first_bad(last: usize, is_bad: &mut impl FnMut(usize) -> Result<bool>) -> Result<usize> {
+    let (mut lo, mut hi) = (0, last);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if is_bad(mid)? {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    Ok(hi)
+}
+
+/// Checks a binary search over `n` candidates needs at most
+fn steps_needed(n: usize) -> usize {
+    (usize::BITS - n.saturating_sub(1).leading_zeros()) as usize
+}
+
+fn is_bad(run: &RunRecord, stage: Option<&str>) -> bool {
+    match stage {
+        Some(name) => run
+            .steps
+            .iter()
+            .any(|s| s.name == name && s.status().is_failure()),
+        None => !run.ok,
+    }
+}
+
+fn resolve(rev: &str) -> Result<String> {
+    let spec = format!("{}^{{commit}}", rev);
+    Ok(workspace::git(&["rev-parse", "--verify", "--quiet", &spec])
+        .with_context(|| format!("unknown revision `{}`", rev))?
+        .trim()
+        .to_string())
+}
+
+fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(10)]
+}
+
+fn subject(commit: &str) -> String {
+    workspace::git(&["log", "-1", "--format=%s", commit])
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// A detached scratch worktree, removed on drop.
+struct Worktree {
+    dir: PathBuf,
+}
+
+impl Worktree {
+    fn add(commit: &str) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!("dwf-bisect-{}", std::process::id()));
+        let path = dir.display().to_string();
+        workspace::git(&["worktree", "add", "--detach", "--quiet", &path, commit])?;
+        Ok(Self { dir })
+    }
+
+    fn checkout(&self, commit: &str) -> Result<()> {
+        let dir = self.dir.display().to_string();
+        workspace::git(&[
+            "-C", &dir, "checkout", "--quiet", "--force", "--detach", commit,
+        ])?;
+        Ok(())
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let path = self.dir.display().to_string();
+        let _ = workspace::git(&["worktree", "remove", "--force", &path]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_search_finds_first_bad_commit() {
+        for first in 0..9 {
+            let mut checked = Vec::new();
+            let got = first_bad(8, &mut |i| {
+                checked.push(i);
+                Ok(i >= first)
+            })
+            .unwrap();
+            assert_eq!(got, first);
+            assert!(checked.len() <= steps_needed(9));
+        }
+        assert_eq!(steps_needed(1), 0);
+        assert_eq!(steps_needed(8), 3);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::config::{self, Config};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+const CACHE_FILE: &str = "cache.json";
+
+/// Inputs assumed for the built-in fmt/check/clippy stages (covers every
+/// workspace member)
+pub const DEFAULT_INPUTS: &[&str] = &[
+    "**/*.rs",
+    "**/Cargo.toml",
+    "Cargo.lock",
+    "rust-toolchain.toml",
+    ".cargo/config.toml",
+    "dwf.toml",
+];
+
+/// Last green input hash per stage, stored as `<storage dir>/cache.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StageCache {
+    #[serde(default)]
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    key: String,
+    duration_ms: u64,
+}
+
+impl StageCache {
+    /// Missing or unreadable cache files just mean nothing is cached yet.
+    pub fn load(cfg: &Config) -> Self {
+        fs::read_to_string(cache_path(cfg))
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    /// Duration of the green run that produced `key`, if it is still current.
+    pub fn hit(&self, stage: &str, key: &str) -> Option<u64> {
+        self.entries
+            .get(stage)
+            .filter(|e| e.key == key)
+            .map(|e| e.duration_ms)
+    }
+
+    pub fn record(&mut self, stage: &str, key: &str, duration_ms: u64) {
+        self.entries.insert(
+            stage.to_string(),
+            CacheEntry {
+                key: key.to_string(),
+                duration_ms,
+            },
+        );
+    }
+
+    pub fn save(&self, cfg: &Config) -> Result<()> {
+        let path = cache_path(cfg);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let s = serde_json::to_string_pretty(self).context("serialize stage cache")?;
+        fs::write(&path, s).with_context(|| format!("write stage cache {:?}", path))?;
+        Ok(())
+    }
+}
+
+fn cache_path(cfg: &Config) -> PathBuf {
+    let (dir, _file) = config::storage_paths(cfg);
+    dir.join(CACHE_FILE)
+}
+
+/// `rustc -vV`, so a toolchain update invalidates every entry.
+pub fn toolchain_version() -> String {
+    Command::new("rustc")
+        .arg("-vV")
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Environment variables that change what cargo builds
+fn affects_build(name: &str) -> bool {
+    name.starts_with("CARGO_")
+        || matches!(
+            name,
+            "RUSTFLAGS" | "RUSTDOCFLAGS" | "RUSTC" | "RUSTC_WRAPPER" | "RUSTC_BOOTSTRAP"
+        )
+}
+
+/// The build-affecting variables among `vars`, sorted, one `NAME=value`
+/// per line. Part of the toolchain passed to `stage_key`.
+pub fn build_env(vars: impl Iterator<Item = (String, String)>) -> String {
+    let mut env: Vec<String> = vars
+        .filter(|(k, _)| affects_build(k))
+        .map(|(k, v)| format!("{}={}\n", k, v))
+        .collect();
+    env.sort();
+    env.concat()
+}
+
+/// Hash of a stage's command line, toolchain and every file matched by `inputs`.
+pub fn stage_key(
+    cfg: &Config,
+    argv: &[String],
+    inputs: &[String],
+    toolchain: &str,
+) -> Result<String> {
+    let mut h = Sha256::new();
+    h.update(argv.join("\0"));
+    h.update([0xff]);
+    h.update(toolchain);
+    h.update([0xff]);
+    for path in input_files(cfg, inputs)? {
+        let bytes = fs::read(&path).with_context(|| format!("read cache input {:?}", path))?;
+        h.update(path.to_string_lossy().as_bytes());
+        h.update([0]);
+        h.update(Sha256::digest(&bytes));
+    }
+    Ok(format!("{:x}", h.finalize()))
+}
+
+/// Files matched by `inputs`, sorted, skipping `.git`, build output and
+/// dwf's own storage. Ignored files still count: Cargo.lock often is one.
+fn input_files(cfg: &Config, inputs: &[String]) -> Result<Vec<PathBuf>> {
+    let patterns = inputs
+        .iter()
+        .map(|p| glob::Pattern::new(p).with_context(|| format!("bad input glob `{}`", p)))
+        .collect::<Result<Vec<_>>>()?;
+    let opts = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+
+    let (storage, _file) = config::storage_paths(cfg);
+    let walker = ignore::WalkBuilder::new(".")
+        .standard_filters(false)
+        .filter_entry(move |e| {
+            let rel = e.path().strip_prefix(".").unwrap_or(e.path());
+            !(rel.starts_with(".git") || rel.starts_with("target") || rel.starts_with(&storage))
+        })
+        .build();
+
+    let mut files = Vec::new();
+    for entry in walker.flatten() {
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let rel = entry
+            .path()
+            .strip_prefix(".")
+            .unwrap_or(entry.path())
+            .to_path_buf();
+        if patterns.iter().any(|p| p.matches_path_with(&rel, opts)) {
+            files.push(rel);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_requires_matching_key() {
+        let mut c = StageCache::default();
+        c.record("check", "abc", 120);
+        assert_eq!(c.hit("check", "abc"), Some(120));
+        assert_eq!(c.hit("check", "def"), None);
+        assert_eq!(c.hit("clippy", "abc"), None);
+    }
+
+    #[test]
+    fn key_depends_on_command_and_toolchain() {
+        let cfg = Config::default();
+        let inputs = vec!["Cargo.toml".to_string()];
+        let a = stage_key(&cfg, &["cargo".into(), "check".into()], &inputs, "1.80").unwrap();
+        let b = stage_key(&cfg, &["cargo".into(), "clippy".into()], &inputs, "1.80").unwrap();
+        let c = stage_key(&cfg, &["cargo".into(), "check".into()], &inputs, "1.81").unwrap();
+        assert_ne!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(
+            a,
+            stage_key(&cfg, &["cargo".into(), "check".into()], &inputs, "1.80").unwrap()
+        );
+
+        let vars = [
+            ("RUSTFLAGS", "-Dwarnings"),
+            ("PATH", "/bin"),
+            ("CARGO_HOME", "/c"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert_eq!(
+            build_env(vars.into_iter()),
+            "CARGO_HOME=/c\nRUSTFLAGS=-Dwarnings\n"
+        );
+    }
+
+    #[test]
+    fn default_inputs_cover_member_sources() {
+        let cfg = Config::default();
+        let inputs: Vec<String> = DEFAULT_INPUTS.iter().map(|p| p.to_string()).collect();
+        let files = input_files(&cfg, &inputs).unwrap();
+        assert!(files.contains(&PathBuf::from("src/main.rs")));
+        assert!(files.contains(&PathBuf::from("Cargo.toml")));
+        assert!(!files.iter().any(|f| f.starts_with("target")));
+    }
+}
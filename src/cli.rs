@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(name = "dwf", version, about = "Developer Workflow Fastlane (Rust)")]
@@ -20,15 +20,31 @@ pub enum Command {
         template: String,
     },
 
-    /// Run the workflow pipeline (fail-fast)
+    /// Run the workflow pipeline (fail-fast unless --keep-going)
     Run {
-        /// Pipeline mode: fast or full
-        #[arg(value_enum, default_value = "fast")]
-        mode: Mode,
+        #[command(flatten)]
+        run: RunArgs,
 
-        /// Do not write to .dwf/history.jsonl
+        /// Apply fixes (cargo fmt, clippy --fix) before checking, like `dwf fix`
         #[arg(long)]
-        no_save: bool,
+        fix: bool,
+
+        /// Print the stage plan (commands, skips, expected durations) without running
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Run each stage's fixer, then the checking pipeline, and list changed files
+    Fix(RunArgs),
+
+    /// Re-run the pipeline whenever project files change
+    Watch {
+        #[command(flatten)]
+        run: RunArgs,
+
+        /// Wait this long for changes to settle before starting a run
+        #[arg(long, default_value_t = 300)]
+        debounce_ms: u64,
     },
 
     /// Print a summary report from recent runs
@@ -36,16 +52,127 @@ pub enum Command {
         /// Number of recent runs to include
         #[arg(long, default_value_t = 10)]
         last: usize,
+
+        /// Only runs of this project (multi-project roots)
+        #[arg(long)]
+        project: Option<String>,
     },
 
     Examples,
 
     /// Suggest improvements based on environment and recent timings
     Doctor,
+
+    /// Find the first commit between --good and --bad where the pipeline fails
+    Bisect {
+        /// Pipeline mode to run on each commit
+        #[arg(default_value = "full")]
+        mode: String,
+
+        /// A commit where the pipeline passes
+        #[arg(long, value_name = "REV")]
+        good: String,
+
+        /// A later commit where it fails
+        #[arg(long, value_name = "REV")]
+        bad: String,
+
+        /// Judge commits by this stage alone
+        #[arg(long)]
+        stage: Option<String>,
+
+        /// Maximum number of stages to run at once (overrides pipeline.jobs)
+        #[arg(long, short = 'j')]
+        jobs: Option<usize>,
+
+        /// Do not write the bisect runs to .dwf/history.jsonl
+        #[arg(long)]
+        no_save: bool,
+    },
+
+    /// Manage git hooks that run dwf before commits and pushes
+    Hooks {
+        #[command(subcommand)]
+        action: HooksAction,
+    },
 }
 
-#[derive(Copy, Clone, Debug, ValueEnum, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
-pub enum Mode {
-    Fast,
-    Full,
+#[derive(Subcommand, Debug)]
+pub enum HooksAction {
+    /// Write hooks that call `dwf run` (default: --pre-commit fast), keeping existing hooks
+    Install {
+        /// Mode to run before each commit
+        #[arg(long, value_name = "MODE")]
+        pre_commit: Option<String>,
+
+        /// Mode to run before each push
+        #[arg(long, value_name = "MODE")]
+        pre_push: Option<String>,
+    },
+
+    /// Remove dwf's hooks and restore the ones they replaced
+    Uninstall,
+
+    /// Show which hooks dwf manages
+    Status,
+}
+
+/// Options shared by `run` and `watch`
+#[derive(clap::Args, Debug, Clone)]
+pub struct RunArgs {
+    /// Pipeline mode: fast, full, or a [modes.<name>] table from dwf.toml
+    #[arg(default_value = "fast")]
+    pub mode: String,
+
+    /// Do not write to .dwf/history.jsonl
+    #[arg(long)]
+    pub no_save: bool,
+
+    /// Maximum number of stages to run at once (overrides pipeline.jobs)
+    #[arg(long, short = 'j')]
+    pub jobs: Option<usize>,
+
+    /// Keep running after a failure and report every failing stage
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Show step output live (default when stdout is a terminal)
+    #[arg(long, overrides_with = "no_stream")]
+    pub stream: bool,
+
+    /// Only show output of failing steps, after they finish
+    #[arg(long)]
+    pub no_stream: bool,
+
+    /// Run every stage even if its inputs are unchanged
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Limit check/clippy/test to crates changed since the base ref
+    #[arg(long)]
+    pub changed: bool,
+
+    /// Git ref to compare against (implies --changed; default: workspace.base)
+    #[arg(long, value_name = "REF")]
+    pub base: Option<String>,
+
+    /// Check what is staged for commit, in a temporary export of the git index
+    #[arg(long)]
+    pub staged: bool,
+
+    /// Fail instead of waiting when another dwf run is busy in this project
+    #[arg(long)]
+    pub no_wait: bool,
+
+    /// What started the run (set by hooks dwf installs), recorded in history
+    #[arg(long, hide = true)]
+    pub trigger: Option<String>,
+
+    /// Set by a multi-project root: record the run under this project name
+    #[arg(long, hide = true)]
+    pub project_name: Option<String>,
+
+    /// Set by a multi-project root: also write the run record to this file
+    #[arg(long, hide = true, value_name = "FILE")]
+    pub record_to: Option<std::path::PathBuf>,
 }
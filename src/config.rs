@@ -1,20 +1,61 @@
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 
 const CONFIG_FILE: &str = "dwf.toml";
 
+/// Stage names used when dwf.toml declares no `[[pipeline.stage]]`
+pub const BUILTIN_STAGES: &[&str] = &["fmt", "check", "clippy", "test"];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub pipeline: PipelineConfig,
     #[serde(default)]
     pub storage: StorageConfig,
+    #[serde(default)]
+    pub workspace: WorkspaceConfig,
+    /// Named modes (`[modes.<name>]`) on top of the built-in fast and full
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub modes: BTreeMap<String, ModeConfig>,
+    /// Projects `dwf run` runs from here instead of a pipeline of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub projects: Option<ProjectsConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProjectsConfig {
+    /// Project directories (each with its own dwf.toml), relative to this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+
+    /// Also run every directory below this one that has a dwf.toml
+    #[serde(default)]
+    pub discover: bool,
+
+    /// Run the projects at the same time
+    #[serde(default)]
+    pub parallel: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModeConfig {
+    /// Stages this mode runs (in pipeline order)
+    pub stages: Vec<String>,
+
+    /// Extra arguments per stage, e.g. `clippy = ["--all-features"]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub args: BTreeMap<String, Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PipelineConfig {
+    /// Stop starting new stages after the first critical failure
+    #[serde(default = "default_true")]
+    pub fail_fast: bool,
+
     /// If true, add --all-features in full mode for clippy/test
     #[serde(default)]
     pub all_features_in_full: bool,
@@ -23,9 +64,171 @@ pub struct PipelineConfig {
     #[serde(default = "default_true")]
     pub clippy_deny_warnings: bool,
 
+    /// Ask the built-in `cargo check`/`cargo clippy` stages for JSON messages
+    /// and record structured diagnostics
+    #[serde(default = "default_true")]
+    pub json_diagnostics: bool,
+
+    /// Skip stages whose inputs are unchanged since they last passed
+    #[serde(default = "default_true")]
+    pub cache: bool,
+
+    /// Inputs of the built-in test stage, which is only cached when set
+    /// (tests may read fixtures and data files of any kind)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub test_inputs: Vec<String>,
+
     /// Trim stderr to this many lines
     #[serde(default = "default_stderr_lines")]
     pub stderr_max_lines: usize,
+
+    /// Default time limit for each stage, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+
+    /// Maximum number of stages running at the same time (default: CPU count)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<usize>,
+
+    /// Retries for the built-in stages; declared stages set their own
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+
+    /// Pause between attempts of a built-in stage (ms)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retry_delay_ms: u64,
+
+    /// Runner for the built-in test stage: "cargo" or "nextest"
+    /// (falls back to `cargo test` when cargo-nextest is not installed)
+    #[serde(default = "default_test_runner")]
+    pub test_runner: String,
+
+    /// Stage order: "declared", or "adaptive" to start independent stages
+    /// that fail often and finish fast first (from recent history)
+    #[serde(default = "default_order")]
+    pub order: String,
+
+    /// Check stages under several feature combinations (`[pipeline.feature_matrix]`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature_matrix: Option<FeatureMatrixConfig>,
+
+    /// User-defined stages (`[[pipeline.stage]]`); empty means the built-in cargo steps
+    #[serde(default, rename = "stage", skip_serializing_if = "Vec::is_empty")]
+    pub stages: Vec<StageConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageConfig {
+    /// Stage name shown in summaries and history
+    pub name: String,
+
+    /// Program and arguments to execute
+    pub cmd: Vec<String>,
+
+    /// If true, a failure stops the pipeline and fails the run
+    #[serde(default = "default_true")]
+    pub critical: bool,
+
+    /// Set to "full" to run the stage only in full mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+
+    /// Stages that must pass before this one starts.
+    /// Omitted means "the previous stage"; `[]` makes the stage independent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub needs: Option<Vec<String>>,
+
+    /// Kill the stage after this many seconds (overrides `pipeline.timeout_secs`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+
+    /// Globs the stage depends on; when set, the stage is skipped while
+    /// they (and the command) are unchanged since it last passed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<String>,
+
+    /// Run a failed stage again up to this many times; passing on a retry
+    /// marks the step flaky
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+
+    /// Pause between attempts (ms)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retry_delay_ms: u64,
+
+    /// Command that fixes what this stage checks, run by `dwf fix`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<Vec<String>>,
+
+    /// Conditions for running the stage at all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<WhenConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WhenConfig {
+    /// Run only if a file matching one of these globs changed since the base
+    /// ref (`--changed`/`--base`) or the last green run of the same mode.
+    /// Globs without `/` match file names in any directory.
+    #[serde(default)]
+    pub changed: Vec<String>,
+}
+
+/// Feature combinations to run cargo stages under, each as its own sub-step.
+/// The default features always run; the rest is opt-in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeatureMatrixConfig {
+    /// Cargo stages to expand
+    #[serde(default = "default_matrix_stages")]
+    pub stages: Vec<String>,
+
+    /// Modes the matrix applies in
+    #[serde(default = "default_matrix_modes")]
+    pub modes: Vec<String>,
+
+    /// Each feature on its own, with default features off
+    #[serde(default)]
+    pub each_feature: bool,
+
+    /// `--no-default-features`
+    #[serde(default)]
+    pub no_default: bool,
+
+    /// `--all-features`
+    #[serde(default)]
+    pub all: bool,
+
+    /// Explicit feature sets, each with default features off
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sets: Vec<Vec<String>>,
+
+    /// Features left out of `each_feature`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip: Vec<String>,
+
+    /// Features that `each_feature` only enables together
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub group: Vec<Vec<String>>,
+}
+
+fn default_matrix_stages() -> Vec<String> {
+    ["check", "clippy", "test"].map(String::from).to_vec()
+}
+
+fn default_matrix_modes() -> Vec<String> {
+    vec!["full".to_string()]
+}
+
+fn is_zero<T: Default + PartialEq>(v: &T) -> bool {
+    *v == T::default()
+}
+
+fn default_order() -> String {
+    "declared".to_string()
+}
+
+fn default_test_runner() -> String {
+    "cargo".to_string()
 }
 
 fn default_true() -> bool {
@@ -39,9 +242,21 @@ fn default_stderr_lines() -> usize {
 impl Default for PipelineConfig {
     fn default() -> Self {
         Self {
+            fail_fast: true,
             all_features_in_full: false,
             clippy_deny_warnings: true,
+            json_diagnostics: true,
+            cache: true,
+            test_inputs: Vec::new(),
             stderr_max_lines: 40,
+            timeout_secs: None,
+            jobs: None,
+            retries: 0,
+            retry_delay_ms: 0,
+            test_runner: default_test_runner(),
+            order: default_order(),
+            feature_matrix: None,
+            stages: Vec::new(),
         }
     }
 }
@@ -74,18 +289,57 @@ impl Default for StorageConfig {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceConfig {
+    /// Limit check/clippy/test to crates changed since `base` and their dependents
+    #[serde(default)]
+    pub changed_only: bool,
+
+    /// Git ref that changes are measured against
+    #[serde(default = "default_base")]
+    pub base: String,
+}
+
+fn default_base() -> String {
+    "origin/main".to_string()
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> Self {
+        Self {
+            changed_only: false,
+            base: default_base(),
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             pipeline: PipelineConfig {
+                fail_fast: true,
                 all_features_in_full: false,
                 clippy_deny_warnings: true,
+                json_diagnostics: true,
+                cache: true,
+                test_inputs: Vec::new(),
                 stderr_max_lines: 80,
+                timeout_secs: None,
+                jobs: None,
+                retries: 0,
+                retry_delay_ms: 0,
+                test_runner: default_test_runner(),
+                order: default_order(),
+                feature_matrix: None,
+                stages: Vec::new(),
             },
             storage: StorageConfig {
                 dir: ".dwf".to_string(),
                 history_file: "history.jsonl".to_string(),
             },
+            workspace: WorkspaceConfig::default(),
+            modes: BTreeMap::new(),
+            projects: None,
         }
     }
 }
@@ -99,9 +353,172 @@ pub fn load_config() -> Result<Config> {
     }
     let s = fs::read_to_string(p).context("read dwf.toml")?;
     let cfg: Config = toml::from_str(&s).context("parse dwf.toml")?;
+    validate(&cfg)?;
     Ok(cfg)
 }
 
+fn validate(cfg: &Config) -> Result<()> {
+    let mut seen = std::collections::BTreeSet::new();
+    for st in &cfg.pipeline.stages {
+        if st.name.trim().is_empty() {
+            return Err(anyhow!("[[pipeline.stage]] is missing a name"));
+        }
+        if !seen.insert(st.name.as_str()) {
+            return Err(anyhow!("duplicate stage name `{}`", st.name));
+        }
+        if st.cmd.is_empty() {
+            return Err(anyhow!("stage `{}` has an empty cmd", st.name));
+        }
+        for g in st.when.iter().flat_map(|w| &w.changed) {
+            glob::Pattern::new(g)
+                .with_context(|| format!("stage `{}`: bad when.changed glob `{}`", st.name, g))?;
+        }
+        if let Some(m) = &st.mode {
+            if m != "fast" && m != "full" {
+                return Err(anyhow!(
+                    "stage `{}`: unknown mode `{}` (expected \"fast\" or \"full\")",
+                    st.name,
+                    m
+                ));
+            }
+        }
+    }
+    for st in &cfg.pipeline.stages {
+        for dep in st.needs.iter().flatten() {
+            if !seen.contains(dep.as_str()) {
+                return Err(anyhow!("stage `{}` needs unknown stage `{}`", st.name, dep));
+            }
+        }
+    }
+    if cfg.pipeline.timeout_secs == Some(0)
+        || cfg
+            .pipeline
+            .stages
+            .iter()
+            .any(|s| s.timeout_secs == Some(0))
+    {
+        return Err(anyhow!("timeout_secs must be at least 1"));
+    }
+    if cfg.pipeline.jobs == Some(0) {
+        return Err(anyhow!("pipeline.jobs must be at least 1"));
+    }
+    if !["cargo", "nextest"].contains(&cfg.pipeline.test_runner.as_str()) {
+        return Err(anyhow!(
+            "pipeline.test_runner must be \"cargo\" or \"nextest\", got \"{}\"",
+            cfg.pipeline.test_runner
+        ));
+    }
+    if !["declared", "adaptive"].contains(&cfg.pipeline.order.as_str()) {
+        return Err(anyhow!(
+            "pipeline.order must be \"declared\" or \"adaptive\", got \"{}\"",
+            cfg.pipeline.order
+        ));
+    }
+    validate_modes(cfg)?;
+    validate_feature_matrix(cfg)?;
+    check_acyclic(&cfg.pipeline.stages)
+}
+
+/// Names of the stages the pipeline can run
+fn stage_names(cfg: &Config) -> Vec<&str> {
+    if cfg.pipeline.stages.is_empty() {
+        BUILTIN_STAGES.to_vec()
+    } else {
+        cfg.pipeline
+            .stages
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect()
+    }
+}
+
+fn validate_feature_matrix(cfg: &Config) -> Result<()> {
+    let Some(m) = &cfg.pipeline.feature_matrix else {
+        return Ok(());
+    };
+    let known = stage_names(cfg);
+    if let Some(st) = m.stages.iter().find(|s| !known.contains(&s.as_str())) {
+        return Err(anyhow!("feature_matrix refers to unknown stage `{}`", st));
+    }
+    let mut grouped = std::collections::BTreeSet::new();
+    for f in m.group.iter().flatten() {
+        if !grouped.insert(f.as_str()) {
+            return Err(anyhow!(
+                "feature `{}` is in more than one feature_matrix group",
+                f
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn validate_modes(cfg: &Config) -> Result<()> {
+    let known = stage_names(cfg);
+    for (name, m) in &cfg.modes {
+        for st in m.stages.iter().chain(m.args.keys()) {
+            if !known.contains(&st.as_str()) {
+                return Err(anyhow!("mode `{}` refers to unknown stage `{}`", name, st));
+            }
+        }
+        if let Some(st) = m.args.keys().find(|k| !m.stages.contains(k)) {
+            return Err(anyhow!(
+                "mode `{}` has args for `{}`, which it does not run",
+                name,
+                st
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Every mode `dwf run` accepts, sorted
+pub fn mode_names(cfg: &Config) -> Vec<String> {
+    let mut names: Vec<String> = cfg.modes.keys().cloned().collect();
+    for builtin in ["fast", "full"] {
+        if !cfg.modes.contains_key(builtin) {
+            names.push(builtin.to_string());
+        }
+    }
+    names.sort();
+    names
+}
+
+fn check_acyclic(stages: &[StageConfig]) -> Result<()> {
+    // Kahn's algorithm: if some stages never become ready, they form a cycle
+    let deps: Vec<Vec<usize>> = stages
+        .iter()
+        .enumerate()
+        .map(|(i, st)| match &st.needs {
+            Some(needs) => needs
+                .iter()
+                .filter_map(|n| stages.iter().position(|s| &s.name == n))
+                .collect(),
+            None => i.checked_sub(1).into_iter().collect(),
+        })
+        .collect();
+
+    let mut done = vec![false; stages.len()];
+    loop {
+        let ready: Vec<usize> = (0..stages.len())
+            .filter(|&i| !done[i] && deps[i].iter().all(|&d| done[d]))
+            .collect();
+        if ready.is_empty() {
+            break;
+        }
+        for i in ready {
+            done[i] = true;
+        }
+    }
+
+    match done.iter().position(|d| !d) {
+        Some(i) => Err(anyhow!(
+            "stage `{}` is part of a dependency cycle",
+            stages[i].name
+        )),
+        None => Ok(()),
+    }
+}
+
 pub fn storage_paths(cfg: &Config) -> (std::path::PathBuf, std::path::PathBuf) {
     let dir = std::path::PathBuf::from(&cfg.storage.dir);
     let file = dir.join(&cfg.storage.history_file);
@@ -119,3 +536,95 @@ pub fn init_config_with(cfg: Config, force: bool) -> Result<()> {
     fs::write(p, toml_str).context("write dwf.toml")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stage_tables() {
+        let s = r#"
+[pipeline]
+[[pipeline.stage]]
+name = "doc"
+cmd = ["cargo", "doc", "--no-deps"]
+
+[[pipeline.stage]]
+name = "deny"
+cmd = ["cargo", "deny", "check"]
+critical = false
+mode = "full"
+"#;
+        let cfg: Config = toml::from_str(s).unwrap();
+        validate(&cfg).unwrap();
+        let st = &cfg.pipeline.stages;
+        assert_eq!(st.len(), 2);
+        assert_eq!(st[0].cmd, vec!["cargo", "doc", "--no-deps"]);
+        assert!(st[0].critical);
+        assert!(!st[1].critical);
+        assert_eq!(st[1].mode.as_deref(), Some("full"));
+    }
+
+    #[test]
+    fn rejects_duplicate_stage_names() {
+        let s = r#"
+[[pipeline.stage]]
+name = "fmt"
+cmd = ["cargo", "fmt"]
+
+[[pipeline.stage]]
+name = "fmt"
+cmd = ["cargo", "fmt", "--check"]
+"#;
+        let cfg: Config = toml::from_str(s).unwrap();
+        assert!(validate(&cfg).is_err());
+    }
+
+    #[test]
+    fn parses_named_modes() {
+        let s = r#"
+[modes.precommit]
+stages = ["fmt", "check"]
+
+[modes.ci]
+stages = ["clippy", "test"]
+args = { clippy = ["--all-features"] }
+"#;
+        let cfg: Config = toml::from_str(s).unwrap();
+        validate(&cfg).unwrap();
+        assert_eq!(cfg.modes["ci"].args["clippy"], ["--all-features"]);
+        assert_eq!(mode_names(&cfg), ["ci", "fast", "full", "precommit"]);
+
+        let bad: Config = toml::from_str("[modes.x]\nstages = [\"deny\"]\n").unwrap();
+        assert!(validate(&bad).is_err());
+    }
+
+    #[test]
+    fn rejects_needs_cycle() {
+        let s = r#"
+[[pipeline.stage]]
+name = "a"
+cmd = ["true"]
+needs = ["b"]
+
+[[pipeline.stage]]
+name = "b"
+cmd = ["true"]
+"#;
+        let cfg: Config = toml::from_str(s).unwrap();
+        let err = validate(&cfg).unwrap_err().to_string();
+        assert!(err.contains("cycle"));
+    }
+
+    #[test]
+    fn rejects_unknown_need() {
+        let s = r#"
+[[pipeline.stage]]
+name = "a"
+cmd = ["true"]
+needs = ["nope"]
+"#;
+        let cfg: Config = toml::from_str(s).unwrap();
+        assert!(validate(&cfg).is_err());
+    }
+}
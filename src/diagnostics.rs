@@ -0,0 +1,145 @@
+use crate::metrics::Diagnostic;
+use crate::workspace;
+use serde_json::Value;
+
+/// Cargo subcommands whose compiler messages we ask for as JSON
+const JSON_SUBCOMMANDS: &[&str] = &["check", "clippy"];
+
+/// True for `cargo check …` / `cargo clippy …` (optionally with a `+toolchain`).
+pub fn is_json_capable(argv: &[String]) -> bool {
+    workspace::cargo_subcommand(argv).is_some_and(|s| JSON_SUBCOMMANDS.contains(&s))
+}
+
+/// Add `--message-format=json` ahead of any `--` separator, unless the
+/// command already picks a message format.
+pub fn with_json_messages(mut argv: Vec<String>) -> Vec<String> {
+    if argv.iter().any(|a| a.starts_with("--message-format")) {
+        return argv;
+    }
+    let at = argv.iter().position(|a| a == "--").unwrap_or(argv.len());
+    argv.insert(at, "--message-format=json".to_string());
+    argv
+}
+
+/// Extract compiler diagnostics from cargo's JSON message stream.
+pub fn parse_messages(stdout: &str) -> Vec<Diagnostic> {
+    let mut out: Vec<Diagnostic> = Vec::new();
+    for line in stdout.lines() {
+        let Some(msg) = compiler_message(line) else {
+            continue;
+        };
+        let Some(d) = to_diagnostic(msg) else {
+            continue;
+        };
+        // The same diagnostic shows up once per target (lib, bin, tests…)
+        if !out.contains(&d) {
+            out.push(d);
+        }
+    }
+    out
+}
+
+/// Human-readable rendering of a JSON message line, for live streaming.
+pub fn rendered(line: &str) -> Option<String> {
+    let msg = compiler_message(line)?;
+    msg.get("rendered")?.as_str().map(|s| s.to_string())
+}
+
+fn compiler_message(line: &str) -> Option<Value> {
+    let v: Value = serde_json::from_str(line.trim()).ok()?;
+    if v.get("reason")?.as_str()? != "compiler-message" {
+        return None;
+    }
+    v.get("message").cloned()
+}
+
+fn to_diagnostic(msg: Value) -> Option<Diagnostic> {
+    let level = msg.get("level")?.as_str()?.to_string();
+    let message = msg.get("message")?.as_str()?.to_string();
+    // Summary lines like "aborting due to 2 previous errors" carry no information
+    if level == "failure-note" || message.starts_with("aborting due to") {
+        return None;
+    }
+
+    let code = msg
+        .get("code")
+        .and_then(|c| c.get("code"))
+        .and_then(Value::as_str)
+        .map(|s| s.to_string());
+    let lint = code.clone().filter(|c| !is_error_code(c));
+
+    let spans = msg.get("spans").and_then(Value::as_array);
+    let primary = spans.and_then(|spans| {
+        spans
+            .iter()
+            .find(|s| s.get("is_primary").and_then(Value::as_bool) == Some(true))
+            .or_else(|| spans.first())
+    });
+    let file = primary
+        .and_then(|s| s.get("file_name"))
+        .and_then(Value::as_str)
+        .map(|s| s.to_string());
+    let line = primary
+        .and_then(|s| s.get("line_start"))
+        .and_then(Value::as_u64)
+        .map(|n| n as u32);
+
+    Some(Diagnostic {
+        level,
+        code,
+        lint,
+        file,
+        line,
+        message,
+    })
+}
+
+/// rustc error codes look like `E0308`; anything else is a lint name.
+fn is_error_code(code: &str) -> bool {
+    code.len() == 5 && code.starts_with('E') && code[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn json_flag_goes_before_separator() {
+        let a = with_json_messages(argv(&["cargo", "clippy", "--", "-D", "warnings"]));
+        assert_eq!(
+            a,
+            argv(&[
+                "cargo",
+                "clippy",
+                "--message-format=json",
+                "--",
+                "-D",
+                "warnings"
+            ])
+        );
+        assert!(is_json_capable(&argv(&["cargo", "+nightly", "check"])));
+        assert!(!is_json_capable(&argv(&["cargo", "fmt", "--check"])));
+    }
+
+    #[test]
+    fn parses_and_dedups_compiler_messages() {
+        let line = r#"{"reason":"compiler-message","message":{"level":"warning","message":"unneeded `return` statement","code":{"code":"clippy::needless_return"},"spans":[{"file_name":"src/lib.rs","line_start":5,"is_primary":true}],"rendered":"warning: unneeded"}}"#;
+        let err = r#"{"reason":"compiler-message","message":{"level":"error","message":"mismatched types","code":{"code":"E0308"},"spans":[{"file_name":"src/main.rs","line_start":10,"is_primary":true}],"rendered":"error[E0308]"}}"#;
+        let abort = r#"{"reason":"compiler-message","message":{"level":"error","message":"aborting due to 1 previous error","code":null,"spans":[],"rendered":"error: aborting"}}"#;
+        let artifact = r#"{"reason":"compiler-artifact","package_id":"x"}"#;
+        let stdout = [line, err, line, abort, artifact].join("\n");
+
+        let d = parse_messages(&stdout);
+        assert_eq!(d.len(), 2);
+        assert_eq!(d[0].lint.as_deref(), Some("clippy::needless_return"));
+        assert_eq!(d[0].line, Some(5));
+        assert_eq!(d[1].code.as_deref(), Some("E0308"));
+        assert_eq!(d[1].lint, None);
+        assert_eq!(d[1].file.as_deref(), Some("src/main.rs"));
+        assert_eq!(rendered(err).as_deref(), Some("error[E0308]"));
+    }
+}
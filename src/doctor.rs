@@ -1,5 +1,7 @@
 use crate::config::Config;
-use crate::metrics::RunRecord;
+use crate::metrics::{RunRecord, StepStatus};
+use crate::nextest;
+use crate::report;
 
 pub fn print_doctor(cfg: &Config, runs: &[RunRecord]) {
     println!("dwf doctor");
@@ -22,6 +24,11 @@ pub fn print_doctor(cfg: &Config, runs: &[RunRecord]) {
         println!("\nDetected RUSTC_WRAPPER: {:?}", rustc_wrapper);
     }
 
+    if cfg.pipeline.test_runner == "nextest" && !nextest::installed() {
+        println!("\nSuggestion: test_runner is \"nextest\" but cargo-nextest is not installed;");
+        println!("  tests fall back to `cargo test`. Install it with `cargo install cargo-nextest --locked`.");
+    }
+
     if runs.is_empty() {
         println!("\nNo recent runs found to analyze. Run `dwf run` a few times first.");
         return;
@@ -33,7 +40,14 @@ pub fn print_doctor(cfg: &Config, runs: &[RunRecord]) {
     let mut test_ms = Vec::new();
 
     for r in runs {
-        for s in &r.steps {
+        // Cached steps repeat an earlier duration, skipped ones took none and
+        // flaky ones add up retries; counting any would skew medians
+        for s in r.steps.iter().filter(|s| {
+            !matches!(
+                s.status(),
+                StepStatus::Cached | StepStatus::Flaky | StepStatus::Skipped | StepStatus::Blocked
+            )
+        }) {
             match s.name.as_str() {
                 "clippy" => clippy_ms.push(s.duration_ms),
                 "check" => check_ms.push(s.duration_ms),
@@ -70,14 +84,30 @@ pub fn print_doctor(cfg: &Config, runs: &[RunRecord]) {
         println!("  then confirm with `dwf run full` before pushing.");
     }
 
+    // Modes are different pipelines; their totals are not comparable
+    let modes = report::by_mode(runs);
+    if modes.len() > 1 {
+        println!("\nRecent total median by mode (ms):");
+        for (mode, rs) in &modes {
+            let totals: Vec<u64> = rs.iter().map(|r| r.total_ms).collect();
+            println!("  {:<10} {} ({} runs)", mode, median(&totals), rs.len());
+        }
+    }
+
+    for hint in resource_hints(runs, total_memory_kb()) {
+        println!("\n{}", hint);
+    }
+
     // Failure stage distribution
     let mut fails = std::collections::BTreeMap::<String, usize>::new();
     for r in runs.iter().filter(|r| !r.ok) {
-        let st = r
-            .failure_stage
-            .clone()
-            .unwrap_or_else(|| "unknown".to_string());
-        *fails.entry(st).or_insert(0) += 1;
+        let stages = r.failed_stages();
+        if stages.is_empty() {
+            *fails.entry("unknown".to_string()).or_insert(0) += 1;
+        }
+        for st in stages {
+            *fails.entry(st).or_insert(0) += 1;
+        }
     }
     if !fails.is_empty() {
         println!("\nFailure stage distribution (recent):");
@@ -87,6 +117,65 @@ pub fn print_doctor(cfg: &Config, runs: &[RunRecord]) {
     }
 }
 
+/// Stages whose recorded CPU, memory and fault counts point at a bottleneck
+/// other than compute.
+fn resource_hints(runs: &[RunRecord], total_memory_kb: Option<u64>) -> Vec<String> {
+    let mut hints = Vec::new();
+    for (stage, samples) in report::stage_usage(runs) {
+        let wall = median(&samples.iter().map(|(w, _)| *w).collect::<Vec<_>>());
+        let cpu = median(&samples.iter().map(|(_, u)| u.cpu_ms()).collect::<Vec<_>>());
+        let rss = median(
+            &samples
+                .iter()
+                .map(|(_, u)| u.max_rss_kb)
+                .collect::<Vec<_>>(),
+        );
+        let faults = median(
+            &samples
+                .iter()
+                .map(|(_, u)| u.major_faults)
+                .collect::<Vec<_>>(),
+        );
+
+        let near_ram = total_memory_kb.is_some_and(|total| rss > total / 2);
+        if near_ram || faults > 1000 {
+            hints.push(format!(
+                "Suggestion: {} is memory-bound (peak {} MiB, {} major page faults).\n  \
+                 Try fewer parallel jobs (e.g. CARGO_BUILD_JOBS) or splitting large test binaries.",
+                stage,
+                rss / 1024,
+                faults
+            ));
+        }
+        // Under a quarter of a core busy for a second or more: it is waiting
+        if wall >= 1000 && cpu * 4 < wall {
+            hints.push(format!(
+                "Suggestion: {} is waiting, not computing (cpu {} ms over {} ms wall).\n  \
+                 Likely I/O, network or another cargo holding the build directory lock.",
+                stage, cpu, wall
+            ));
+        }
+    }
+    hints
+}
+
+#[cfg(unix)]
+fn total_memory_kb() -> Option<u64> {
+    // SAFETY: sysconf only reads system configuration
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    (pages > 0 && page_size > 0).then(|| pages as u64 * page_size as u64 / 1024)
+}
+
+#[cfg(not(unix))]
+fn total_memory_kb() -> Option<u64> {
+    None
+}
+
 fn median(v: &[u64]) -> u64 {
     if v.is_empty() {
         return 0;
@@ -100,3 +189,41 @@ fn median(v: &[u64]) -> u64 {
         (s[mid - 1] / 2) + (s[mid] / 2) + ((s[mid - 1] % 2 + s[mid] % 2) / 2)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{ResourceUsage, StepStatus};
+
+    fn run_using(stage: &str, wall: u64, usage: ResourceUsage) -> RunRecord {
+        let mut r = RunRecord::for_test("full", &[(stage, StepStatus::Passed, wall)]);
+        r.steps[0].resources = Some(usage);
+        r
+    }
+
+    #[test]
+    fn flags_memory_bound_and_waiting_stages() {
+        let heavy = ResourceUsage {
+            user_ms: 9000,
+            max_rss_kb: 6 * 1024 * 1024,
+            ..Default::default()
+        };
+        let idle = ResourceUsage {
+            user_ms: 100,
+            max_rss_kb: 50 * 1024,
+            ..Default::default()
+        };
+        let runs = vec![
+            run_using("test", 5000, heavy),
+            run_using("check", 4000, idle),
+        ];
+
+        let hints = resource_hints(&runs, Some(8 * 1024 * 1024));
+        assert_eq!(hints.len(), 2);
+        assert!(hints[0].starts_with("Suggestion: check is waiting"));
+        assert!(hints[1].starts_with("Suggestion: test is memory-bound"));
+        assert!(resource_hints(&runs, Some(64 * 1024 * 1024))
+            .iter()
+            .all(|h| !h.contains("memory-bound")));
+    }
+}
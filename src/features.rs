@@ -0,0 +1,116 @@
+use crate::config::FeatureMatrixConfig;
+
+/// One way of building a package: extra cargo flags and a short label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Combination {
+    /// "default", "no-default", "all" or the enabled features joined by `+`
+    pub label: String,
+    pub args: Vec<String>,
+}
+
+/// Combinations `m` asks for, for a package declaring `features`: default
+/// features first, then no-default, single features (or groups), explicit
+/// sets and all features. Sets naming a feature the package lacks are left
+/// out, and duplicates are dropped.
+pub fn combinations(m: &FeatureMatrixConfig, features: &[String]) -> Vec<Combination> {
+    let mut out = vec![Combination {
+        label: "default".to_string(),
+        args: Vec::new(),
+    }];
+    if m.no_default {
+        out.push(only(&[]));
+    }
+    if m.each_feature {
+        let mut done: Vec<&String> = Vec::new();
+        for f in features {
+            if m.skip.contains(f) || done.contains(&f) {
+                continue;
+            }
+            let unit: Vec<String> = match m.group.iter().find(|g| g.contains(f)) {
+                Some(g) => g.iter().filter(|x| features.contains(x)).cloned().collect(),
+                None => vec![f.clone()],
+            };
+            done.extend(features.iter().filter(|x| unit.contains(x)));
+            out.push(only(&unit));
+        }
+    }
+    for set in &m.sets {
+        if set.iter().all(|f| features.contains(f)) {
+            out.push(only(set));
+        }
+    }
+    if m.all {
+        out.push(Combination {
+            label: "all".to_string(),
+            args: vec!["--all-features".to_string()],
+        });
+    }
+
+    let mut unique: Vec<Combination> = Vec::new();
+    for c in out {
+        if !unique.iter().any(|u| u.args == c.args) {
+            unique.push(c);
+        }
+    }
+    unique
+}
+
+/// Exactly `features`, without the defaults
+fn only(features: &[String]) -> Combination {
+    if features.is_empty() {
+        return Combination {
+            label: "no-default".to_string(),
+            args: vec!["--no-default-features".to_string()],
+        };
+    }
+    Combination {
+        label: features.join("+"),
+        args: vec![
+            "--no-default-features".to_string(),
+            "--features".to_string(),
+            features.join(","),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn each_feature_honours_skip_group_and_sets() {
+        let m = FeatureMatrixConfig {
+            stages: names(&["check"]),
+            modes: names(&["full"]),
+            each_feature: true,
+            no_default: true,
+            all: true,
+            sets: vec![names(&["std", "serde"]), names(&["nightly"]), Vec::new()],
+            skip: names(&["unstable"]),
+            group: vec![names(&["rustls", "ring"])],
+        };
+        let features = names(&["ring", "rustls", "serde", "std", "unstable"]);
+        let got: Vec<String> = combinations(&m, &features)
+            .into_iter()
+            .map(|c| c.label)
+            .collect();
+        assert_eq!(
+            got,
+            [
+                "default",
+                "no-default",
+                "rustls+ring",
+                "serde",
+                "std",
+                "std+serde",
+                "all"
+            ]
+        );
+        let serde = &combinations(&m, &features)[3];
+        assert_eq!(serde.args, ["--no-default-features", "--features", "serde"]);
+    }
+}
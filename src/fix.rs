@@ -0,0 +1,114 @@
+use crate::config::{self, Config};
+use crate::workspace;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Content hash of project files, keyed by path relative to the root
+pub type Snapshot = BTreeMap<PathBuf, Vec<u8>>;
+
+/// Hash the files below `root` that differ from git's index. A file a
+/// fixer changes shows up in the later snapshot even if it was clean
+/// before, so only dirty files need hashing. Outside git, every file is
+/// hashed.
+pub fn snapshot(cfg: &Config, root: &Path) -> Snapshot {
+    let paths = dirty_files(root).unwrap_or_else(|| all_files(cfg, root));
+    paths
+        .into_iter()
+        .filter_map(|p| {
+            let bytes = fs::read(root.join(&p)).ok()?;
+            Some((p, Sha256::digest(&bytes).to_vec()))
+        })
+        .collect()
+}
+
+/// Modified and untracked (not ignored) files below `root`
+fn dirty_files(root: &Path) -> Option<Vec<PathBuf>> {
+    let root = root.to_string_lossy();
+    let out = workspace::git(&[
+        "-C",
+        &root,
+        "ls-files",
+        "-z",
+        "--modified",
+        "--others",
+        "--exclude-standard",
+    ])
+    .ok()?;
+    Some(
+        out.split('\0')
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+    )
+}
+
+/// Everything below `root` not ignored by .gitignore, outside `target/`
+/// and the dwf storage dir.
+fn all_files(cfg: &Config, root: &Path) -> Vec<PathBuf> {
+    let (storage, _file) = config::storage_paths(cfg);
+    let base = root.to_path_buf();
+    let walker = ignore::WalkBuilder::new(root)
+        .require_git(false)
+        .filter_entry(move |e| {
+            let rel = e.path().strip_prefix(&base).unwrap_or(e.path());
+            !(rel.starts_with("target") || rel.starts_with(&storage))
+        })
+        .build();
+
+    walker
+        .flatten()
+        .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+        .map(|e| {
+            e.path()
+                .strip_prefix(root)
+                .unwrap_or(e.path())
+                .to_path_buf()
+        })
+        .collect()
+}
+
+/// Files whose contents differ between the snapshots, or that only one has.
+pub fn changed_files(before: &Snapshot, after: &Snapshot) -> Vec<String> {
+    let mut changed: Vec<String> = after
+        .iter()
+        .filter(|(path, hash)| before.get(*path) != Some(hash))
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+    changed.extend(
+        before
+            .keys()
+            .filter(|path| !after.contains_key(*path))
+            .map(|path| path.display().to_string()),
+    );
+    changed.sort();
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_modified_added_and_removed_files() {
+        let before: Snapshot = [
+            (PathBuf::from("src/a.rs"), vec![1]),
+            (PathBuf::from("src/b.rs"), vec![2]),
+            (PathBuf::from("src/c.rs"), vec![3]),
+        ]
+        .into_iter()
+        .collect();
+        let after: Snapshot = [
+            (PathBuf::from("src/a.rs"), vec![1]),
+            (PathBuf::from("src/b.rs"), vec![9]),
+            (PathBuf::from("src/d.rs"), vec![4]),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            changed_files(&before, &after),
+            ["src/b.rs", "src/c.rs", "src/d.rs"]
+        );
+    }
+}
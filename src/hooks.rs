@@ -0,0 +1,221 @@
+use crate::config::{self, Config};
+use crate::workspace;
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Hooks dwf knows how to install
+pub const HOOKS: &[&str] = &["pre-commit", "pre-push"];
+
+/// First comment line of every script dwf writes
+const MARKER: &str = "# Installed by dwf";
+
+/// Suffix an existing hook is renamed to; the dwf hook runs it first
+const CHAINED: &str = ".dwf-chained";
+
+pub fn install(cfg: &Config, hooks: &[(&str, &str)]) -> Result<()> {
+    let modes = config::mode_names(cfg);
+    for (_, mode) in hooks {
+        if !modes.iter().any(|m| m == mode) {
+            return Err(anyhow!(
+                "unknown mode `{}` (available: {})",
+                mode,
+                modes.join(", ")
+            ));
+        }
+    }
+    let dir = hooks_dir()?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("create {:?}", dir))?;
+    let exe = std::env::current_exe()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| "dwf".to_string());
+    let prefix = workspace::git(&["rev-parse", "--show-prefix"])?;
+    for (hook, mode) in hooks {
+        let chained = install_hook(&dir, hook, &script(hook, mode, &exe, prefix.trim()))?;
+        if chained {
+            println!("{}: runs `dwf run {}` after the existing hook", hook, mode);
+        } else {
+            println!("{}: runs `dwf run {}`", hook, mode);
+        }
+    }
+    Ok(())
+}
+
+pub fn uninstall() -> Result<()> {
+    let dir = hooks_dir()?;
+    for hook in HOOKS {
+        match uninstall_hook(&dir, hook)? {
+            Removed::NotInstalled => {}
+            Removed::Restored => println!("{}: removed, previous hook restored", hook),
+            Removed::Deleted => println!("{}: removed", hook),
+        }
+    }
+    Ok(())
+}
+
+pub fn print_status() -> Result<()> {
+    let dir = hooks_dir()?;
+    println!("Hooks in {}:", dir.display());
+    for hook in HOOKS {
+        let path = dir.join(hook);
+        let Ok(body) = std::fs::read_to_string(&path) else {
+            println!("  {:<10} not installed", hook);
+            continue;
+        };
+        if !is_managed(&body) {
+            println!("  {:<10} not managed by dwf", hook);
+            continue;
+        }
+        let mode = body
+            .lines()
+            .find_map(|l| l.strip_prefix("# dwf mode: "))
+            .unwrap_or("?");
+        let chain = if chained_path(&dir, hook).exists() {
+            ", after the previous hook"
+        } else {
+            ""
+        };
+        println!("  {:<10} dwf run {}{}", hook, mode, chain);
+    }
+    Ok(())
+}
+
+/// Git's hooks directory (honours `core.hooksPath` and worktrees).
+fn hooks_dir() -> Result<PathBuf> {
+    let dir = workspace::git(&["rev-parse", "--git-path", "hooks"])
+        .context("dwf hooks needs a git repository")?;
+    Ok(PathBuf::from(dir.trim()))
+}
+
+fn is_managed(body: &str) -> bool {
+    body.lines().nth(1).is_some_and(|l| l.starts_with(MARKER))
+}
+
+fn chained_path(dir: &Path, hook: &str) -> PathBuf {
+    dir.join(format!("{}{}", hook, CHAINED))
+}
+
+/// Hook script that runs any chained hook, then `dwf run <mode>` from the
+/// directory holding dwf.toml (`prefix`, relative to the work tree).
+/// Before a commit only the staged changes are checked.
+fn script(hook: &str, mode: &str, exe: &str, prefix: &str) -> String {
+    let staged = if hook == "pre-commit" {
+        "--staged "
+    } else {
+        ""
+    };
+    format!(
+        "#!/bin/sh\n\
+         {marker}; `dwf hooks uninstall` restores any hook it replaced\n\
+         # dwf mode: {mode}\n\
+         chained=\"$(dirname \"$0\")/{hook}{chained}\"\n\
+         if [ -x \"$chained\" ]; then\n\
+         \x20   \"$chained\" \"$@\" || exit $?\n\
+         fi\n\
+         cd \"$(git rev-parse --show-toplevel)\"/{prefix} || exit 1\n\
+         exec {exe} run {mode_arg} {staged}--trigger {hook}\n",
+        marker = MARKER,
+        mode = mode,
+        hook = hook,
+        chained = CHAINED,
+        prefix = sh_quote(prefix),
+        exe = sh_quote(exe),
+        mode_arg = sh_quote(mode),
+        staged = staged,
+    )
+}
+
+fn sh_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=+".contains(c))
+    {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Write `body` as `hook`, moving a hook dwf did not write out of the way
+/// first. Returns whether a previous hook is chained.
+fn install_hook(dir: &Path, hook: &str, body: &str) -> Result<bool> {
+    let path = dir.join(hook);
+    let chained = chained_path(dir, hook);
+    if let Ok(existing) = std::fs::read_to_string(&path) {
+        if !is_managed(&existing) {
+            if chained.exists() {
+                return Err(anyhow!(
+                    "{} exists and so does {:?}; move one of them away first",
+                    hook,
+                    chained
+                ));
+            }
+            std::fs::rename(&path, &chained)
+                .with_context(|| format!("move {:?} to {:?}", path, chained))?;
+        }
+    }
+    std::fs::write(&path, body).with_context(|| format!("write {:?}", path))?;
+    make_executable(&path)?;
+    Ok(chained.exists())
+}
+
+enum Removed {
+    NotInstalled,
+    Deleted,
+    Restored,
+}
+
+fn uninstall_hook(dir: &Path, hook: &str) -> Result<Removed> {
+    let path = dir.join(hook);
+    match std::fs::read_to_string(&path) {
+        Ok(body) if is_managed(&body) => {}
+        _ => return Ok(Removed::NotInstalled),
+    }
+    std::fs::remove_file(&path).with_context(|| format!("remove {:?}", path))?;
+    let chained = chained_path(dir, hook);
+    if chained.exists() {
+        std::fs::rename(&chained, &path).with_context(|| format!("restore {:?}", chained))?;
+        return Ok(Removed::Restored);
+    }
+    Ok(Removed::Deleted)
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("make {:?} executable", path))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn install_chains_existing_hook_and_uninstall_restores_it() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("pre-commit"), "#!/bin/sh\necho mine\n").unwrap();
+
+        let body = script("pre-commit", "fast", "/opt/my tools/dwf", "crates/it's/");
+        assert!(body.contains(r#"cd "$(git rev-parse --show-toplevel)"/'crates/it'\''s/' ||"#));
+        assert!(body.contains("exec '/opt/my tools/dwf' run fast --staged --trigger pre-commit"));
+        assert!(script("pre-push", "full", "dwf", "").contains(r#"--show-toplevel)"/'' ||"#));
+        assert!(install_hook(dir, "pre-commit", &body).unwrap());
+        // Reinstalling keeps the chained hook instead of chaining dwf onto itself
+        assert!(install_hook(dir, "pre-commit", &body).unwrap());
+        assert!(is_managed(
+            &std::fs::read_to_string(dir.join("pre-commit")).unwrap()
+        ));
+
+        assert!(matches!(
+            uninstall_hook(dir, "pre-commit").unwrap(),
+            Removed::Restored
+        ));
+        let restored = std::fs::read_to_string(dir.join("pre-commit")).unwrap();
+        assert!(restored.contains("echo mine"));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::libtest::{MESSAGE_LINES, SLOWEST};
+use crate::metrics::{FailedTest, TestSummary, TestTiming};
+use anyhow::{Context, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::path::Path;
+
+/// Test results from a JUnit report such as the one cargo-nextest writes.
+pub fn read(path: &Path) -> Result<TestSummary> {
+    let xml =
+        std::fs::read_to_string(path).with_context(|| format!("read JUnit report {:?}", path))?;
+    parse(&xml).with_context(|| format!("parse JUnit report {:?}", path))
+}
+
+#[derive(Default)]
+struct Case {
+    /// `classname::name`, since the same test name can occur in several
+    /// binaries (nextest's classname is the binary)
+    name: String,
+    duration_ms: Option<u64>,
+    failed: bool,
+    skipped: bool,
+    message: String,
+}
+
+fn parse(xml: &str) -> Result<TestSummary> {
+    let mut reader = Reader::from_str(xml);
+    let mut summary = TestSummary::default();
+    let mut timings: Vec<TestTiming> = Vec::new();
+    let mut case: Option<Case> = None;
+    // Inside <failure>/<error>, whose text is the panic output
+    let mut in_failure = false;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => match e.name().as_ref() {
+                b"testcase" => case = Some(start_case(&e)?),
+                b"failure" | b"error" => {
+                    if let Some(c) = &mut case {
+                        c.failed = true;
+                        in_failure = true;
+                    }
+                }
+                b"skipped" => {
+                    if let Some(c) = &mut case {
+                        c.skipped = true;
+                    }
+                }
+                _ => {}
+            },
+            Event::Empty(e) => match e.name().as_ref() {
+                b"testcase" => finish(&mut summary, &mut timings, start_case(&e)?),
+                b"failure" | b"error" => {
+                    if let Some(c) = &mut case {
+                        c.failed = true;
+                        if let Some(m) = attr(&e, b"message")? {
+                            c.message = m;
+                        }
+                    }
+                }
+                b"skipped" => {
+                    if let Some(c) = &mut case {
+                        c.skipped = true;
+                    }
+                }
+                _ => {}
+            },
+            Event::Text(t) if in_failure => {
+                if let Some(c) = &mut case {
+                    c.message.push_str(&t.unescape()?);
+                }
+            }
+            Event::CData(t) if in_failure => {
+                if let Some(c) = &mut case {
+                    c.message.push_str(&String::from_utf8_lossy(&t));
+                }
+            }
+            Event::End(e) => match e.name().as_ref() {
+                b"failure" | b"error" => in_failure = false,
+                b"testcase" => {
+                    if let Some(c) = case.take() {
+                        finish(&mut summary, &mut timings, c);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    timings.sort_by_key(|t| std::cmp::Reverse(t.duration_ms));
+    timings.truncate(SLOWEST);
+    summary.slowest = timings;
+    Ok(summary)
+}
+
+fn start_case(e: &BytesStart) -> Result<Case> {
+    let name = attr(e, b"name")?.unwrap_or_default();
+    let name = match attr(e, b"classname")? {
+        Some(class) if !class.is_empty() => format!("{}::{}", class, name),
+        _ => name,
+    };
+    Ok(Case {
+        name,
+        duration_ms: attr(e, b"time")?
+            .and_then(|t| t.parse::<f64>().ok())
+            .map(|secs| (secs * 1000.0).round() as u64),
+        ..Default::default()
+    })
+}
+
+fn attr(e: &BytesStart, key: &[u8]) -> Result<Option<String>> {
+    for a in e.attributes() {
+        let a = a?;
+        if a.key.as_ref() == key {
+            return Ok(Some(a.unescape_value()?.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn finish(summary: &mut TestSummary, timings: &mut Vec<TestTiming>, c: Case) {
+    if c.skipped {
+        summary.ignored += 1;
+        return;
+    }
+    if let Some(ms) = c.duration_ms {
+        timings.push(TestTiming {
+            name: c.name.clone(),
+            duration_ms: ms,
+        });
+    }
+    if !c.failed {
+        summary.passed += 1;
+        return;
+    }
+    summary.failed += 1;
+    let message = c
+        .message
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .take_while(|l| !l.starts_with("stack backtrace:"))
+        .take(MESSAGE_LINES)
+        .collect::<Vec<_>>()
+        .join("\n");
+    summary.failures.push(FailedTest {
+        name: c.name,
+        duration_ms: c.duration_ms,
+        message,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nextest_report() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="nextest-run" tests="4" failures="1" errors="0" time="1.305">
+    <testsuite name="m13" tests="4" disabled="1" errors="0" failures="1">
+        <testcase name="tests::good" classname="m13" time="0.004">
+        </testcase>
+        <testcase name="tests::slow" classname="m13" time="1.300"/>
+        <testcase name="tests::bad" classname="m13" time="0.012">
+            <failure type="test failure">thread &apos;tests::bad&apos; panicked at src/lib.rs:7:9:
+assertion `left == right` failed
+  left: 1
+ right: 2</failure>
+            <system-out></system-out>
+        </testcase>
+        <testcase name="tests::later" classname="m13" time="0.000">
+            <skipped/>
+        </testcase>
+    </testsuite>
+    <testsuite name="m13::it" tests="1" disabled="0" errors="0" failures="1">
+        <testcase name="tests::bad" classname="m13::it" time="0.001">
+            <failure message="assertion failed"/>
+        </testcase>
+    </testsuite>
+</testsuites>"#;
+        let t = parse(xml).unwrap();
+        assert_eq!((t.passed, t.failed, t.ignored), (2, 2, 1));
+        assert_eq!(t.failures[0].name, "m13::tests::bad");
+        assert_eq!(t.failures[0].duration_ms, Some(12));
+        assert!(t.failures[0].message.starts_with("thread 'tests::bad'"));
+        // Same test name in another binary
+        assert_eq!(t.failures[1].name, "m13::it::tests::bad");
+        assert_eq!(t.failures[1].message, "assertion failed");
+        assert_eq!(t.slowest[0].name, "m13::tests::slow");
+        assert_eq!(t.slowest[0].duration_ms, 1300);
+    }
+}
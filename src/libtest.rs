@@ -0,0 +1,221 @@
+use crate::metrics::{FailedTest, TestSummary, TestTiming};
+
+/// Lines of a failing test's captured output kept in history (also for
+/// JUnit reports)
+pub(crate) const MESSAGE_LINES: usize = 6;
+
+/// Slowest tests kept per step
+pub(crate) const SLOWEST: usize = 10;
+
+/// Results from libtest's human-readable output (any verbosity), summed over
+/// every test binary. `None` when the output has no `test result:` line,
+/// e.g. because the build failed first.
+pub fn parse(stdout: &str) -> Option<TestSummary> {
+    let mut summary = TestSummary::default();
+    let mut saw_result = false;
+    let mut names: Vec<String> = Vec::new();
+    let mut durations: Vec<(String, u64)> = Vec::new();
+    let mut messages: Vec<(String, Vec<String>)> = Vec::new();
+
+    // `---- name stdout ----` sections, then a `failures:` list of names
+    let mut section: Option<(String, Vec<String>)> = None;
+    let mut in_list = false;
+
+    for line in stdout.lines() {
+        if let Some(rest) = line.strip_prefix("test result: ") {
+            saw_result = true;
+            in_list = false;
+            add_counts(&mut summary, rest);
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix("---- ")
+            .and_then(|l| l.strip_suffix(" stdout ----"))
+        {
+            messages.extend(section.take());
+            section = Some((name.to_string(), Vec::new()));
+            continue;
+        }
+        if line == "failures:" {
+            messages.extend(section.take());
+            in_list = true;
+            continue;
+        }
+        if in_list {
+            if let Some(name) = line.strip_prefix("    ") {
+                names.push(name.trim().to_string());
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            // The first `failures:` heads the captured output, not a list
+            in_list = false;
+        }
+        if let Some((_, out)) = &mut section {
+            // The panic message is what matters; a backtrace would crowd it out
+            let in_backtrace = out
+                .last()
+                .is_some_and(|l| l.starts_with("stack backtrace:"));
+            if !in_backtrace
+                && !line.starts_with("note: run with `RUST_BACKTRACE")
+                && !line.trim().is_empty()
+            {
+                out.push(line.to_string());
+            }
+            continue;
+        }
+        // `test name ... FAILED`, with `<0.123s>` under --report-time
+        if let Some((name, outcome)) = line
+            .strip_prefix("test ")
+            .and_then(|l| l.rsplit_once(" ... "))
+        {
+            let mut words = outcome.split_whitespace();
+            let verdict = words.next().unwrap_or_default();
+            if verdict == "FAILED" {
+                names.push(name.to_string());
+            }
+            if let Some(ms) = words.next().and_then(parse_time) {
+                durations.push((name.to_string(), ms));
+            }
+        }
+    }
+    messages.extend(section);
+
+    if !saw_result {
+        return None;
+    }
+    for name in names {
+        if summary.failures.iter().any(|f| f.name == name) {
+            continue;
+        }
+        let duration_ms = durations
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, ms)| *ms);
+        let message = messages
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, out)| {
+                out.iter()
+                    .take_while(|l| !l.starts_with("stack backtrace:"))
+                    .take(MESSAGE_LINES)
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .unwrap_or_default();
+        summary.failures.push(FailedTest {
+            name,
+            duration_ms,
+            message,
+        });
+    }
+    durations.sort_by_key(|d| std::cmp::Reverse(d.1));
+    summary.slowest = durations
+        .into_iter()
+        .take(SLOWEST)
+        .map(|(name, duration_ms)| TestTiming { name, duration_ms })
+        .collect();
+    Some(summary)
+}
+
+/// `ok. 3 passed; 1 failed; 0 ignored; ...`
+fn add_counts(summary: &mut TestSummary, result: &str) {
+    let counts = result.split_once(". ").map_or(result, |(_, c)| c);
+    for part in counts.split(';') {
+        let mut words = part.split_whitespace();
+        let (Some(n), Some(what)) = (words.next(), words.next()) else {
+            continue;
+        };
+        let Ok(n) = n.parse::<u32>() else { continue };
+        match what {
+            "passed" => summary.passed += n,
+            "failed" => summary.failed += n,
+            "ignored" => summary.ignored += n,
+            _ => {}
+        }
+    }
+}
+
+/// `<0.123s>` → 123
+fn parse_time(s: &str) -> Option<u64> {
+    let secs: f64 = s.strip_prefix('<')?.strip_suffix("s>")?.parse().ok()?;
+    Some((secs * 1000.0).round() as u64)
+}
+
+/// Merge results from several invocations (e.g. one per workspace package).
+pub fn merge(into: &mut Option<TestSummary>, other: Option<TestSummary>) {
+    let Some(other) = other else { return };
+    match into {
+        Some(t) => {
+            t.passed += other.passed;
+            t.failed += other.failed;
+            t.ignored += other.ignored;
+            t.failures.extend(other.failures);
+            t.slowest.extend(other.slowest);
+            t.slowest.sort_by_key(|t| std::cmp::Reverse(t.duration_ms));
+            t.slowest.truncate(SLOWEST);
+        }
+        None => *into = Some(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quiet_output_across_binaries() {
+        let out = "
+running 3 tests
+.F.
+failures:
+
+---- config::tests::parses stdout ----
+
+thread 'config::tests::parses' panicked at src/config.rs:10:5:
+assertion `left == right` failed
+  left: 1
+ right: 2
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+
+failures:
+    config::tests::parses
+
+test result: FAILED. 2 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.01s
+
+running 2 tests
+i.
+test result: ok. 1 passed; 0 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.00s
+";
+        let t = parse(out).unwrap();
+        assert_eq!((t.passed, t.failed, t.ignored), (3, 1, 1));
+        assert_eq!(t.failures.len(), 1);
+        assert_eq!(t.failures[0].name, "config::tests::parses");
+        assert!(t.failures[0].message.contains("left: 1"));
+        assert!(!t.failures[0].message.contains("RUST_BACKTRACE"));
+    }
+
+    #[test]
+    fn parses_verbose_lines_with_report_time() {
+        let out = "
+running 2 tests
+test a::slow ... FAILED <1.250s>
+test a::fast ... ok <0.001s>
+
+failures:
+
+failures:
+    a::slow
+
+test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out; finished in 1.25s
+";
+        let t = parse(out).unwrap();
+        assert_eq!(t.failures[0].name, "a::slow");
+        assert_eq!(t.failures[0].duration_ms, Some(1250));
+        assert_eq!(t.slowest[1].name, "a::fast");
+        assert!(parse("error[E0425]: cannot find value").is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::config::{self, Config};
+use anyhow::{anyhow, Context, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Held by whichever dwf process is running a pipeline in the project
+const RUN_LOCK: &str = "run.lock";
+
+/// Held by a process waiting for the run lock; a watch that sees it busy
+/// steps aside
+const QUEUE_LOCK: &str = "queue.lock";
+
+/// How often a waiting process retries
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What holds the run lock, as shown to processes that have to wait.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Holder {
+    Run,
+    Watch,
+    Bisect,
+}
+
+impl Holder {
+    fn as_str(self) -> &'static str {
+        match self {
+            Holder::Run => "run",
+            Holder::Watch => "watch",
+            Holder::Bisect => "bisect",
+        }
+    }
+}
+
+/// Exclusive right to run a pipeline in this project; released on drop.
+pub struct RunLock {
+    file: File,
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        // Whoever waits next must not read us as the holder; closing the
+        // file then releases the lock
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Take the project's run lock (a file in the storage dir). When another
+/// dwf holds it, wait for it to finish, or fail right away if `wait` is
+/// false; a watch run in progress is cut short instead. Returns None if
+/// `abort` is set while waiting.
+pub fn acquire(
+    cfg: &Config,
+    holder: Holder,
+    wait: bool,
+    abort: Option<&AtomicBool>,
+) -> Result<Option<RunLock>> {
+    acquire_in(&config::storage_paths(cfg).0, holder, wait, abort)
+}
+
+/// Whether another process is waiting for the run lock. Watch checks this
+/// while it runs and gives way.
+pub fn preempt_requested(cfg: &Config) -> bool {
+    queue_busy(&config::storage_paths(cfg).0)
+}
+
+fn acquire_in(
+    dir: &Path,
+    holder: Holder,
+    wait: bool,
+    abort: Option<&AtomicBool>,
+) -> Result<Option<RunLock>> {
+    std::fs::create_dir_all(dir).with_context(|| format!("create {:?}", dir))?;
+    let run = open(&dir.join(RUN_LOCK))?;
+    // Waiters queue up first, so a preempted watch cannot grab the run
+    // lock again before them
+    let queue = open(&dir.join(QUEUE_LOCK))?;
+    let mut told = false;
+    for file in [&queue, &run] {
+        while !try_lock(file)? {
+            if !told {
+                let (who, watch) = current_holder(dir);
+                if !wait && !watch {
+                    return Err(anyhow!(
+                        "{} is already running in this project (drop --no-wait to wait for it)",
+                        who
+                    ));
+                }
+                if watch {
+                    eprintln!("dwf: stopping {} so this run can go first", who);
+                } else {
+                    eprintln!("dwf: waiting for {} to finish…", who);
+                }
+                told = true;
+            }
+            if abort.is_some_and(|a| a.load(Ordering::SeqCst)) {
+                return Ok(None);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+    drop(queue);
+
+    // Say who we are, for anyone who has to wait
+    run.set_len(0).context("truncate run lock")?;
+    writeln!(&run, "{} {}", std::process::id(), holder.as_str()).context("write run lock")?;
+    Ok(Some(RunLock { file: run }))
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("open lock file {:?}", path))
+}
+
+fn try_lock(file: &File) -> Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e).context("lock file"),
+    }
+}
+
+fn queue_busy(dir: &Path) -> bool {
+    // Our own probe unlocks again when the file is closed
+    open(&dir.join(QUEUE_LOCK))
+        .and_then(|f| try_lock(&f))
+        .is_ok_and(|free| !free)
+}
+
+/// Description of the run lock's holder, and whether it is a watch.
+fn current_holder(dir: &Path) -> (String, bool) {
+    let body = std::fs::read_to_string(dir.join(RUN_LOCK)).unwrap_or_default();
+    match body.split_whitespace().collect::<Vec<_>>()[..] {
+        [pid, kind] => (format!("dwf {} (pid {})", kind, pid), kind == "watch"),
+        _ => ("another dwf process".to_string(), false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_wait_fails_on_a_run_but_a_watch_gives_way() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join(".dwf");
+        let held = acquire_in(&dir, Holder::Run, false, None).unwrap();
+        let busy = acquire_in(&dir, Holder::Run, false, None).err().unwrap();
+        assert!(busy.to_string().contains("dwf run (pid"));
+        drop(held);
+        assert_eq!(current_holder(&dir).0, "another dwf process");
+
+        let watch = acquire_in(&dir, Holder::Watch, true, None).unwrap();
+        assert!(!queue_busy(&dir));
+        let got = std::thread::scope(|s| {
+            let waiter = s.spawn(|| acquire_in(&dir, Holder::Run, false, None));
+            while !queue_busy(&dir) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            drop(watch);
+            waiter.join().unwrap()
+        });
+        assert!(got.unwrap().is_some());
+    }
+}
@@ -1,34 +1,90 @@
+mod bisect;
+mod cache;
 mod cli;
 mod config;
+mod diagnostics;
 mod doctor;
+mod features;
+mod fix;
+mod hooks;
+mod junit;
+mod libtest;
+mod lock;
 mod metrics;
+mod nextest;
+mod projects;
 mod report;
 mod runner;
+mod staged;
 mod templates;
+mod watch;
+mod workspace;
 
 use anyhow::Result;
 use clap::Parser;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 fn main() -> Result<()> {
     let args = cli::Args::parse();
 
     match args.command {
-        cli::Command::Run { mode, no_save } => {
+        cli::Command::Run {
+            run: run_args,
+            fix,
+            dry_run: true,
+        } => {
             let cfg = config::load_config()?;
-            let run = runner::run_pipeline(&cfg, mode)?;
-            runner::print_run_summary(&run);
-
-            if !no_save {
-                metrics::append_run(&run)?;
+            if orchestrates(&cfg, &run_args) {
+                // Each project prints its own plan
+                let list = projects::projects(&cfg, Path::new("."))?;
+                projects::run_all(&cfg, &list)?;
+                return Ok(());
             }
-
-            // Exit non-zero if pipeline failed (useful for CI)
-            if !run.ok {
-                std::process::exit(1);
+            let opts = runner::RunOptions {
+                fix,
+                ..run_options(&run_args)?
+            };
+            let history = metrics::load_last_runs(runner::HISTORY_WINDOW).unwrap_or_default();
+            runner::print_plan(&cfg, &run_args.mode, &opts, &history)?;
+        }
+        cli::Command::Run {
+            run: run_args, fix, ..
+        } => run(&run_args, fix)?,
+        cli::Command::Fix(run_args) => run(&run_args, true)?,
+        cli::Command::Watch {
+            run: run_args,
+            debounce_ms,
+        } => {
+            if run_args.staged {
+                return Err(anyhow::anyhow!("--staged is not supported by watch"));
+            }
+            let cfg = config::load_config()?;
+            if orchestrates(&cfg, &run_args) {
+                return Err(anyhow::anyhow!(
+                    "watch runs a single project; start it in the project's directory"
+                ));
             }
+            watch::watch(
+                cfg,
+                &run_args.mode,
+                run_options(&run_args)?,
+                !run_args.no_save,
+                Duration::from_millis(debounce_ms),
+            )?;
         }
-        cli::Command::Report { last } => {
-            let runs = metrics::load_last_runs(last)?;
+        cli::Command::Report { last, project } => {
+            let runs = match &project {
+                Some(name) => {
+                    let mut runs = metrics::load_last_runs(usize::MAX)?;
+                    runs.retain(|r| r.project.as_deref() == Some(name.as_str()));
+                    runs.split_off(runs.len().saturating_sub(last))
+                }
+                None => metrics::load_last_runs(last)?,
+            };
             report::print_report(&runs, last);
         }
         cli::Command::Doctor => {
@@ -36,6 +92,55 @@ fn main() -> Result<()> {
             let runs = metrics::load_last_runs(20).unwrap_or_default();
             doctor::print_doctor(&cfg, &runs);
         }
+        cli::Command::Bisect {
+            mode,
+            good,
+            bad,
+            stage,
+            jobs,
+            no_save,
+        } => {
+            let cfg = config::load_config()?;
+            let args = bisect::BisectArgs {
+                good: &good,
+                bad: &bad,
+                mode: &mode,
+                stage: stage.as_deref(),
+                save: !no_save,
+            };
+            let opts = runner::RunOptions {
+                jobs,
+                abort: Some(interrupt_flag()?),
+                ..Default::default()
+            };
+            let Some(_lock) =
+                lock::acquire(&cfg, lock::Holder::Bisect, true, opts.abort.as_deref())?
+            else {
+                std::process::exit(130);
+            };
+            bisect::bisect(&cfg, &args, opts)?;
+        }
+        cli::Command::Hooks { action } => match action {
+            cli::HooksAction::Install {
+                pre_commit,
+                pre_push,
+            } => {
+                let cfg = config::load_config()?;
+                let mut hooks = Vec::new();
+                if let Some(mode) = &pre_commit {
+                    hooks.push(("pre-commit", mode.as_str()));
+                }
+                if let Some(mode) = &pre_push {
+                    hooks.push(("pre-push", mode.as_str()));
+                }
+                if hooks.is_empty() {
+                    hooks.push(("pre-commit", "fast"));
+                }
+                hooks::install(&cfg, &hooks)?;
+            }
+            cli::HooksAction::Uninstall => hooks::uninstall()?,
+            cli::HooksAction::Status => hooks::print_status()?,
+        },
         cli::Command::Examples => {
             for t in templates::list_templates() {
                 println!("{}", t);
@@ -51,3 +156,121 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn run(run_args: &cli::RunArgs, fix: bool) -> Result<()> {
+    let cfg = config::load_config()?;
+    if orchestrates(&cfg, run_args) {
+        return run_projects(&cfg, run_args);
+    }
+    if run_args.staged && fix {
+        return Err(anyhow::anyhow!(
+            "--staged cannot be combined with fixing: fixes would land in a temporary copy"
+        ));
+    }
+    let mut opts = runner::RunOptions {
+        fix,
+        ..run_options(run_args)?
+    };
+    let wait = !run_args.no_wait;
+    let Some(_lock) = lock::acquire(&cfg, lock::Holder::Run, wait, opts.abort.as_deref())? else {
+        std::process::exit(130);
+    };
+    let staged = if run_args.staged {
+        Some(staged::export()?)
+    } else {
+        None
+    };
+    if let Some(s) = &staged {
+        opts.workdir = Some(s.workdir.clone());
+        opts.staged = true;
+        // Build into the usual target directory, reusing earlier artifacts
+        opts.target_dir = workspace::target_dir().ok();
+    }
+    let mut run = runner::run_pipeline(&cfg, &run_args.mode, &opts)?;
+    run.tree = staged.as_ref().map(|s| s.tree.clone());
+    run.project = run_args.project_name.clone();
+    // Remove the export now; exiting below skips destructors
+    drop(staged);
+    runner::print_run_summary(&run, opts.stream);
+
+    if !run_args.no_save {
+        metrics::append_run(&run)?;
+    }
+    if let Some(path) = &run_args.record_to {
+        std::fs::write(path, serde_json::to_string(&run)?)?;
+    }
+
+    // Exit non-zero if pipeline failed (useful for CI); 130 like a shell
+    // reports an interrupted command
+    if run.aborted {
+        std::process::exit(130);
+    }
+    if !run.ok {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// A root with `[projects]` runs those instead of a pipeline of its own;
+/// the runs it starts carry a project name and run their own pipeline.
+fn orchestrates(cfg: &config::Config, run_args: &cli::RunArgs) -> bool {
+    cfg.projects.is_some() && run_args.project_name.is_none()
+}
+
+/// Run every project, print a combined summary and keep each project's
+/// run in this directory's history too, so `report --project` can find it.
+fn run_projects(cfg: &config::Config, run_args: &cli::RunArgs) -> Result<()> {
+    let list = projects::projects(cfg, Path::new("."))?;
+    // The projects see Ctrl-C themselves; stay alive to record what they did
+    let interrupted = interrupt_flag()?;
+    let runs = projects::run_all(cfg, &list)?;
+    projects::print_summary(&run_args.mode, &runs);
+
+    if !run_args.no_save {
+        for r in runs.iter().filter_map(|p| p.record.as_ref()) {
+            metrics::append_run(r)?;
+        }
+    }
+    let aborted = runs
+        .iter()
+        .any(|p| p.record.as_ref().is_some_and(|r| r.aborted));
+    if aborted || interrupted.load(Ordering::SeqCst) {
+        std::process::exit(130);
+    }
+    if !runs.iter().all(|p| p.record.as_ref().is_some_and(|r| r.ok)) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_options(run_args: &cli::RunArgs) -> Result<runner::RunOptions> {
+    Ok(runner::RunOptions {
+        jobs: run_args.jobs,
+        keep_going: run_args.keep_going,
+        stream: run_args.stream || (!run_args.no_stream && std::io::stdout().is_terminal()),
+        no_cache: run_args.no_cache,
+        abort: Some(interrupt_flag()?),
+        fix: false,
+        changed: run_args.changed,
+        base: run_args.base.clone(),
+        trigger: run_args.trigger.clone(),
+        workdir: None,
+        staged: false,
+        target_dir: None,
+        commit: None,
+        only: None,
+        capture: None,
+    })
+}
+
+/// Ctrl-C and SIGTERM set the returned flag, so the runner can kill its
+/// children and the run is still recorded. A second signal exits at once.
+fn interrupt_flag() -> Result<Arc<AtomicBool>> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let flag = Arc::new(AtomicBool::new(false));
+    for sig in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(sig, 130, flag.clone())?;
+        signal_hook::flag::register(sig, flag.clone())?;
+    }
+    Ok(flag)
+}
@@ -1,4 +1,3 @@
-use crate::cli::Mode;
 use crate::config;
 use crate::config::Config;
 use anyhow::{Context, Result};
@@ -6,6 +5,66 @@ use serde::{Deserialize, Serialize};
 use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, Write};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Passed,
+    Failed,
+    /// Killed because another critical stage failed
+    Cancelled,
+    /// Killed after exceeding its time limit
+    TimedOut,
+    /// Not run: inputs unchanged since it last passed
+    Cached,
+    /// Passed, but only after failing at least once
+    Flaky,
+    /// Not run: its `when` conditions were not met
+    Skipped,
+    /// Not run: a stage it needs failed
+    Blocked,
+}
+
+impl StepStatus {
+    /// Failures that count toward TTS and the failing-stage list
+    pub fn is_failure(self) -> bool {
+        matches!(self, StepStatus::Failed | StepStatus::TimedOut)
+    }
+
+    /// Outcomes that let dependent stages start
+    pub fn is_success(self) -> bool {
+        matches!(
+            self,
+            StepStatus::Passed | StepStatus::Cached | StepStatus::Flaky | StepStatus::Skipped
+        )
+    }
+}
+
+/// A compiler message parsed from cargo's JSON output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// "error", "warning", "note", …
+    pub level: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Lint name when the code is a lint rather than an error code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+/// One invocation inside a split step (e.g. a single workspace package).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubStepRecord {
+    pub name: String,
+    pub ok: bool,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StepRecord {
     pub name: String,
@@ -13,18 +72,250 @@ pub struct StepRecord {
     pub exit_code: Option<i32>,
     pub duration_ms: u64,
     pub stderr_excerpt: String,
+    #[serde(default = "default_true")]
+    pub critical: bool,
+    /// Missing in records written before stages could be cancelled; see `status()`
+    #[serde(default)]
+    pub status: Option<StepStatus>,
+    /// Offset from the start of the run (ms)
+    #[serde(default)]
+    pub started_ms: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+    /// Per-invocation timings when the step ran once per package
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub substeps: Vec<SubStepRecord>,
+    /// Every attempt, oldest first, when the stage was retried
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<AttemptRecord>,
+    /// Parsed libtest results, when the step ran tests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tests: Option<TestSummary>,
+    /// CPU, memory and I/O of the step's processes (unix only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceUsage>,
+    /// Why the step was skipped or blocked, e.g. "no relevant changes"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<String>,
+}
+
+/// Resource usage of a command and every descendant it waited for
+/// (cargo's rustc and test processes included), from `wait4`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub user_ms: u64,
+    pub sys_ms: u64,
+    /// Peak resident set size of the largest single process (KiB)
+    pub max_rss_kb: u64,
+    /// Page faults that had to read from disk (a sign of swapping)
+    #[serde(default)]
+    pub major_faults: u64,
+    /// Filesystem blocks read and written
+    #[serde(default)]
+    pub block_in: u64,
+    #[serde(default)]
+    pub block_out: u64,
+}
+
+impl ResourceUsage {
+    pub fn cpu_ms(&self) -> u64 {
+        self.user_ms + self.sys_ms
+    }
+
+    /// Combine usage of commands run one after another
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.user_ms += other.user_ms;
+        self.sys_ms += other.sys_ms;
+        self.max_rss_kb = self.max_rss_kb.max(other.max_rss_kb);
+        self.major_faults += other.major_faults;
+        self.block_in += other.block_in;
+        self.block_out += other.block_out;
+    }
+}
+
+/// Test counts for a step, summed over every test binary it ran.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TestSummary {
+    pub passed: u32,
+    pub failed: u32,
+    pub ignored: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<FailedTest>,
+    /// Longest-running tests first, when the runner reports per-test times
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slowest: Vec<TestTiming>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestTiming {
+    pub name: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedTest {
+    pub name: String,
+    /// Known from nextest's JUnit report or libtest's `--report-time`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Start of the test's captured output (panic message)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+/// One try of a retried stage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttemptRecord {
+    pub status: StepStatus,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stderr_excerpt: String,
+}
+
+impl StepRecord {
+    pub fn status(&self) -> StepStatus {
+        match self.status {
+            Some(st) => st,
+            None if self.ok => StepStatus::Passed,
+            None => StepStatus::Failed,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RunRecord {
     pub timestamp_rfc3339: String,
-    pub mode: Mode,
+    /// Mode name; records from before named modes said "Fast"/"Full"
+    #[serde(deserialize_with = "mode_name")]
+    pub mode: String,
     pub ok: bool,
     pub tts_ms: u64,
     pub ttg_ms: Option<u64>,
     pub total_ms: u64,
     pub steps: Vec<StepRecord>,
     pub failure_stage: Option<String>,
+    /// Every stage that failed, in plan order (keep-going runs can have several)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failure_stages: Vec<String>,
+    /// Workspace packages the run was limited to (None = whole workspace)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packages: Option<Vec<String>>,
+    /// Fixers ran before the checks (`dwf fix` / `dwf run --fix`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub autofix: bool,
+    /// Files the fixers changed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fixed_files: Vec<String>,
+    /// Interrupted (Ctrl-C / SIGTERM) before the pipeline finished
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub aborted: bool,
+    /// Stages in the order `order = "adaptive"` chose, with the history behind it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub order: Vec<StageRank>,
+    /// HEAD when the run started; `when.changed` diffs against the last green one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Git tree that was checked (`--staged`); None means the working tree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree: Option<String>,
+    /// Project of a multi-project root (`[projects]`) the run belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// Git hook that started the run; None for runs started by hand
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trigger: Option<String>,
+}
+
+/// Why a stage got its place in an adaptive order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageRank {
+    pub name: String,
+    /// Recent runs that executed the stage, and how many of them failed
+    pub runs: u32,
+    pub failures: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_ms: Option<u64>,
+}
+
+impl RunRecord {
+    /// Failed stages, falling back to `failure_stage` for older records
+    pub fn failed_stages(&self) -> Vec<String> {
+        if self.failure_stages.is_empty() {
+            self.failure_stage.iter().cloned().collect()
+        } else {
+            self.failure_stages.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+impl RunRecord {
+    /// A run of `mode` whose steps ended with the given status after the
+    /// given time (ms); everything else is left empty.
+    pub fn for_test(mode: &str, steps: &[(&str, StepStatus, u64)]) -> Self {
+        let steps: Vec<StepRecord> = steps
+            .iter()
+            .map(|&(name, status, duration_ms)| StepRecord {
+                name: name.to_string(),
+                ok: status.is_success(),
+                exit_code: Some(i32::from(status.is_failure())),
+                duration_ms,
+                stderr_excerpt: String::new(),
+                critical: true,
+                status: Some(status),
+                started_ms: 0,
+                diagnostics: Vec::new(),
+                substeps: Vec::new(),
+                attempts: Vec::new(),
+                tests: None,
+                resources: None,
+                skip_reason: None,
+            })
+            .collect();
+        let failure_stages: Vec<String> = steps
+            .iter()
+            .filter(|s| s.status().is_failure())
+            .map(|s| s.name.clone())
+            .collect();
+        let ok = failure_stages.is_empty();
+        let total_ms = steps.iter().map(|s| s.duration_ms).sum();
+        RunRecord {
+            timestamp_rfc3339: "2024-01-01T00:00:00Z".to_string(),
+            mode: mode.to_string(),
+            ok,
+            tts_ms: total_ms,
+            ttg_ms: ok.then_some(total_ms),
+            total_ms,
+            steps,
+            failure_stage: failure_stages.first().cloned(),
+            failure_stages,
+            packages: None,
+            autofix: false,
+            fixed_files: Vec::new(),
+            aborted: false,
+            order: Vec::new(),
+            commit: None,
+            tree: None,
+            project: None,
+            trigger: None,
+        }
+    }
+}
+
+/// Legacy "Fast"/"Full" become the built-in mode names; custom modes keep
+/// their case, since that is how dwf.toml spells them
+fn mode_name<'de, D: serde::Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    let mode = String::deserialize(d)?;
+    Ok(match mode.as_str() {
+        "Fast" => "fast".to_string(),
+        "Full" => "full".to_string(),
+        _ => mode,
+    })
 }
 
 fn load_cfg_for_storage() -> Result<Config> {
@@ -47,6 +338,8 @@ pub fn append_run(run: &RunRecord) -> Result<()> {
         .append(true)
         .open(&file)
         .with_context(|| format!("open history file {:?}", file))?;
+    // Runs finishing together must not interleave their lines
+    f.lock().context("lock history file")?;
 
     let line = serde_json::to_string(run).context("serialize run to json")?;
     writeln!(f, "{}", line).context("write history line")?;
@@ -88,3 +381,22 @@ pub fn load_last_runs(last: usize) -> Result<Vec<RunRecord>> {
     let start = runs.len().saturating_sub(last);
     Ok(runs[start..].to_vec())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_legacy_mode_names_are_lowercased() {
+        let mode = |m: &str| {
+            let line = format!(
+                r#"{{"timestamp_rfc3339":"t","mode":"{}","ok":true,"tts_ms":1,"ttg_ms":1,"total_ms":1,"steps":[],"failure_stage":null}}"#,
+                m
+            );
+            serde_json::from_str::<RunRecord>(&line).unwrap().mode
+        };
+        assert_eq!(mode("Fast"), "fast");
+        assert_eq!(mode("Full"), "full");
+        assert_eq!(mode("CI"), "CI");
+    }
+}
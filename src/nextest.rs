@@ -0,0 +1,53 @@
+use crate::config::{self, Config};
+use crate::workspace;
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// nextest profile dwf adds through a tool config file; the repo's own
+/// `.config/nextest.toml` stays in charge of everything else
+pub const PROFILE: &str = "dwf-junit";
+
+const TOOL_CONFIG: &str = "nextest.toml";
+
+/// Whether `cargo nextest` is available.
+pub fn installed() -> bool {
+    Command::new("cargo")
+        .args(["nextest", "--version"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// Absolute path of the tool config dwf passes with `--tool-config-file`.
+pub fn tool_config_path(cfg: &Config) -> PathBuf {
+    let (dir, _file) = config::storage_paths(cfg);
+    std::env::current_dir()
+        .unwrap_or_default()
+        .join(dir)
+        .join(TOOL_CONFIG)
+}
+
+pub fn write_tool_config(cfg: &Config) -> Result<()> {
+    let path = tool_config_path(cfg);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let body = format!(
+        "# Written by dwf: a profile that also records JUnit results\n\
+         [profile.{}.junit]\n\
+         path = \"junit.xml\"\n",
+        PROFILE
+    );
+    std::fs::write(&path, body).with_context(|| format!("write {:?}", path))
+}
+
+/// Where nextest writes the JUnit report for `PROFILE`, under the target
+/// directory cargo reports (honouring `build.target-dir` and friends).
+pub fn junit_path() -> Result<PathBuf> {
+    Ok(workspace::target_dir()?
+        .join("nextest")
+        .join(PROFILE)
+        .join("junit.xml"))
+}
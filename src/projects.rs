@@ -0,0 +1,221 @@
+use crate::config::{Config, ProjectsConfig};
+use crate::metrics::RunRecord;
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const CONFIG_FILE: &str = "dwf.toml";
+
+#[derive(Debug, Clone)]
+pub struct Project {
+    /// Directory relative to the root, with `/` separators
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+/// How one project's run went.
+pub struct ProjectRun {
+    pub name: String,
+    /// None when the project never got to record a run (e.g. a config error)
+    pub record: Option<RunRecord>,
+    pub exit_code: Option<i32>,
+}
+
+/// Projects of the `[projects]` root at `root`: listed members in order,
+/// then discovered ones sorted by name.
+pub fn projects(cfg: &Config, root: &Path) -> Result<Vec<Project>> {
+    list(&cfg.projects.clone().unwrap_or_default(), root)
+}
+
+fn list(pc: &ProjectsConfig, root: &Path) -> Result<Vec<Project>> {
+    let mut out: Vec<Project> = Vec::new();
+    for m in &pc.members {
+        let dir = root.join(m);
+        if !dir.join(CONFIG_FILE).is_file() {
+            return Err(anyhow!("project `{}` has no {}", m, CONFIG_FILE));
+        }
+        out.push(Project {
+            name: m.trim_end_matches('/').to_string(),
+            dir,
+        });
+    }
+    if pc.discover {
+        for p in discover(root) {
+            if !out.iter().any(|o| o.name == p.name) {
+                out.push(p);
+            }
+        }
+    }
+    if out.is_empty() {
+        return Err(anyhow!(
+            "[projects] lists no members and discovery found no dwf.toml below {:?}",
+            root
+        ));
+    }
+    Ok(out)
+}
+
+/// Directories below `root` with a dwf.toml, skipping ignored files, build
+/// output and dwf's storage.
+fn discover(root: &Path) -> Vec<Project> {
+    let walker = ignore::WalkBuilder::new(root)
+        .require_git(false)
+        .filter_entry(|e| !matches!(e.file_name().to_str(), Some("target" | ".dwf" | ".git")))
+        .build();
+    let mut found: Vec<Project> = walker
+        .flatten()
+        .filter(|e| e.file_name() == CONFIG_FILE && e.depth() > 1)
+        .filter_map(|e| {
+            let dir = e.path().parent()?.to_path_buf();
+            let rel = dir.strip_prefix(root).ok()?;
+            let name = rel
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            Some(Project { name, dir })
+        })
+        .collect();
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    found
+}
+
+/// Run this same dwf command line in every project directory, one after
+/// another with live output, or all at once with each project's output
+/// printed as a block. Every project runs, whatever the others do.
+pub fn run_all(cfg: &Config, projects: &[Project]) -> Result<Vec<ProjectRun>> {
+    let parallel = cfg.projects.as_ref().is_some_and(|p| p.parallel);
+    let exe = std::env::current_exe().context("locate the dwf executable")?;
+    let forwarded: Vec<std::ffi::OsString> = std::env::args_os().skip(1).collect();
+    let record_file = |i: usize| {
+        std::env::temp_dir().join(format!("dwf-project-{}-{}.json", std::process::id(), i))
+    };
+    let command = |i: usize, p: &Project| {
+        let mut cmd = Command::new(&exe);
+        cmd.args(&forwarded)
+            .arg("--project-name")
+            .arg(&p.name)
+            .arg("--record-to")
+            .arg(record_file(i))
+            .current_dir(&p.dir)
+            .stdin(Stdio::null());
+        cmd
+    };
+
+    let exit_codes: Vec<Option<i32>> = if parallel {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = projects
+                .iter()
+                .enumerate()
+                .map(|(i, p)| {
+                    let mut cmd = command(i, p);
+                    scope.spawn(move || cmd.output())
+                })
+                .collect();
+            let mut codes = Vec::new();
+            for (p, h) in projects.iter().zip(handles) {
+                let out = h
+                    .join()
+                    .map_err(|_| anyhow!("project `{}`: runner thread panicked", p.name))?
+                    .with_context(|| format!("run dwf in {:?}", p.dir))?;
+                println!("== {} ==", p.name);
+                print!("{}", String::from_utf8_lossy(&out.stdout));
+                eprint!("{}", String::from_utf8_lossy(&out.stderr));
+                codes.push(out.status.code());
+            }
+            Ok::<_, anyhow::Error>(codes)
+        })?
+    } else {
+        let mut codes = Vec::new();
+        for (i, p) in projects.iter().enumerate() {
+            println!("== {} ==", p.name);
+            let status = command(i, p)
+                .status()
+                .with_context(|| format!("run dwf in {:?}", p.dir))?;
+            codes.push(status.code());
+        }
+        codes
+    };
+
+    Ok(projects
+        .iter()
+        .zip(exit_codes)
+        .enumerate()
+        .map(|(i, (p, exit_code))| {
+            let file = record_file(i);
+            let record = std::fs::read_to_string(&file)
+                .ok()
+                .and_then(|s| serde_json::from_str(&s).ok());
+            let _ = std::fs::remove_file(&file);
+            ProjectRun {
+                name: p.name.clone(),
+                record,
+                exit_code,
+            }
+        })
+        .collect())
+}
+
+pub fn print_summary(mode: &str, runs: &[ProjectRun]) {
+    println!();
+    println!("Projects ({} mode):", mode);
+    for p in runs {
+        match &p.record {
+            Some(r) => {
+                let status = if r.aborted {
+                    "⏹️"
+                } else if r.ok {
+                    "✅"
+                } else {
+                    "❌"
+                };
+                let failed = r.failed_stages();
+                let detail = if failed.is_empty() {
+                    String::new()
+                } else {
+                    format!("  failed: {}", failed.join(", "))
+                };
+                println!(
+                    "  {} {:<16} TTS {} ms  total {} ms{}",
+                    status, p.name, r.tts_ms, r.total_ms, detail
+                );
+            }
+            None => println!("  ❌ {:<16} did not run (exit={:?})", p.name, p.exit_code),
+        }
+    }
+    let ok = runs
+        .iter()
+        .filter(|p| p.record.as_ref().is_some_and(|r| r.ok))
+        .count();
+    println!("All projects: {}/{} ok", ok, runs.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_finds_nested_projects_but_not_build_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for dir in ["", "api", "tools/gen", "api/target/pkg", "web/.dwf"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::write(root.join(dir).join(CONFIG_FILE), "").unwrap();
+        }
+        let pc = ProjectsConfig {
+            members: vec!["tools/gen".to_string()],
+            discover: true,
+            parallel: false,
+        };
+        let got = list(&pc, root).map(|ps| ps.into_iter().map(|p| p.name).collect::<Vec<_>>());
+        let missing = list(
+            &ProjectsConfig {
+                members: vec!["nope".to_string()],
+                ..Default::default()
+            },
+            root,
+        );
+        assert_eq!(got.unwrap(), ["tools/gen", "api"]);
+        assert!(missing.is_err());
+    }
+}
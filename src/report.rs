@@ -1,4 +1,4 @@
-use crate::metrics::RunRecord;
+use crate::metrics::{ResourceUsage, RunRecord, StepStatus};
 
 pub fn print_report(runs: &[RunRecord], last: usize) {
     if runs.is_empty() {
@@ -7,8 +7,11 @@ pub fn print_report(runs: &[RunRecord], last: usize) {
     }
 
     let count = runs.len();
-    let tts: Vec<u64> = runs.iter().map(|r| r.tts_ms).collect();
-    let ttg: Vec<u64> = runs.iter().filter_map(|r| r.ttg_ms).collect();
+    // Interrupted runs say nothing about how long the pipeline takes
+    let aborted = runs.iter().filter(|r| r.aborted).count();
+    let finished: Vec<&RunRecord> = runs.iter().filter(|r| !r.aborted).collect();
+    let tts: Vec<u64> = finished.iter().map(|r| r.tts_ms).collect();
+    let ttg: Vec<u64> = finished.iter().filter_map(|r| r.ttg_ms).collect();
 
     let avg_tts = avg(&tts);
     let med_tts = median(&tts);
@@ -24,33 +27,217 @@ pub fn print_report(runs: &[RunRecord], last: usize) {
         Some(median(&ttg))
     };
 
-    let ok_count = runs.iter().filter(|r| r.ok).count();
-    let fail_count = count - ok_count;
+    let ok_count = finished.iter().filter(|r| r.ok).count();
+    let fail_count = finished.len() - ok_count;
 
     let mut fail_stage_counts = std::collections::BTreeMap::<String, usize>::new();
-    for r in runs.iter().filter(|r| !r.ok) {
-        let k = r
-            .failure_stage
-            .clone()
-            .unwrap_or_else(|| "unknown".to_string());
-        *fail_stage_counts.entry(k).or_insert(0) += 1;
+    for r in finished.iter().filter(|r| !r.ok) {
+        let stages = r.failed_stages();
+        if stages.is_empty() {
+            *fail_stage_counts.entry("unknown".to_string()).or_insert(0) += 1;
+        }
+        for k in stages {
+            *fail_stage_counts.entry(k).or_insert(0) += 1;
+        }
     }
 
     println!("Report (last {} requested, found {}):", last, count);
-    println!("  ok: {} | failed: {}", ok_count, fail_count);
-    println!("  TTS  avg: {} ms | median: {} ms", avg_tts, med_tts);
+    if aborted > 0 {
+        println!(
+            "  ok: {} | failed: {} | aborted: {}",
+            ok_count, fail_count, aborted
+        );
+    } else {
+        println!("  ok: {} | failed: {}", ok_count, fail_count);
+    }
+    if tts.is_empty() {
+        println!("  TTS  (no finished runs in sample)");
+    } else {
+        println!("  TTS  avg: {} ms | median: {} ms", avg_tts, med_tts);
+    }
 
     match (avg_ttg, med_ttg) {
         (Some(a), Some(m)) => println!("  TTG  avg: {} ms | median: {} ms", a, m),
         _ => println!("  TTG  (no green runs in sample)"),
     }
 
+    let modes = by_mode(runs);
+    if modes.len() > 1 {
+        println!("  By mode:");
+        for (mode, rs) in &modes {
+            let ok = rs.iter().filter(|r| r.ok).count();
+            let ttg: Vec<u64> = rs.iter().filter_map(|r| r.ttg_ms).collect();
+            let ttg = if ttg.is_empty() {
+                "-".to_string()
+            } else {
+                format!("{} ms", median(&ttg))
+            };
+            println!(
+                "    {:<10} runs: {} | ok: {} | TTG median: {}",
+                mode,
+                rs.len(),
+                ok,
+                ttg
+            );
+        }
+    }
+
+    print_groups("By trigger", &by_trigger(runs));
+    print_groups("By project", &by_project(runs));
+
+    // Adaptive runs record their order; compare their TTS with the rest
+    let (adaptive, declared): (Vec<&RunRecord>, Vec<&RunRecord>) =
+        finished.iter().partition(|r| !r.order.is_empty());
+    if !adaptive.is_empty() && !declared.is_empty() {
+        let tts = |rs: &[&RunRecord]| median(&rs.iter().map(|r| r.tts_ms).collect::<Vec<_>>());
+        println!(
+            "  TTS by order: adaptive median {} ms ({} runs) | declared median {} ms ({} runs)",
+            tts(&adaptive),
+            adaptive.len(),
+            tts(&declared),
+            declared.len()
+        );
+    }
+
     if !fail_stage_counts.is_empty() {
         println!("  Failure stages:");
         for (stage, n) in fail_stage_counts {
             println!("    {:<8} {}", stage, n);
         }
     }
+
+    let usage = stage_usage(runs);
+    if !usage.is_empty() {
+        println!("  CPU vs wall (median per stage):");
+        for (stage, samples) in &usage {
+            let wall = median(&samples.iter().map(|(w, _)| *w).collect::<Vec<_>>());
+            let cpu = median(&samples.iter().map(|(_, u)| u.cpu_ms()).collect::<Vec<_>>());
+            let rss = median(
+                &samples
+                    .iter()
+                    .map(|(_, u)| u.max_rss_kb)
+                    .collect::<Vec<_>>(),
+            );
+            println!(
+                "    {:<8} wall {} ms | cpu {} ms ({:.1}x) | peak {} MiB",
+                stage,
+                wall,
+                cpu,
+                cpu as f64 / wall.max(1) as f64,
+                rss / 1024
+            );
+        }
+    }
+
+    let flakes: Vec<_> = flake_rates(runs)
+        .into_iter()
+        .filter(|(_, (flaky, _))| *flaky > 0)
+        .collect();
+    if !flakes.is_empty() {
+        println!("  Flaky stages (passed only on retry):");
+        for (stage, (flaky, ran)) in flakes {
+            println!(
+                "    {:<8} {}/{} runs ({}%)",
+                stage,
+                flaky,
+                ran,
+                flaky * 100 / ran
+            );
+        }
+    }
+}
+
+/// Per stage: (flaky runs, runs where the stage actually executed)
+fn flake_rates(runs: &[RunRecord]) -> std::collections::BTreeMap<String, (usize, usize)> {
+    let mut rates = std::collections::BTreeMap::<String, (usize, usize)>::new();
+    for s in runs.iter().flat_map(|r| &r.steps) {
+        let status = s.status();
+        if matches!(
+            status,
+            StepStatus::Cached | StepStatus::Cancelled | StepStatus::Skipped | StepStatus::Blocked
+        ) {
+            continue;
+        }
+        let e = rates.entry(s.name.clone()).or_insert((0, 0));
+        e.1 += 1;
+        if status == StepStatus::Flaky {
+            e.0 += 1;
+        }
+    }
+    rates
+}
+
+/// Per stage: wall time and resource usage of every run that executed it
+/// to the end (cached, cancelled and pre-usage records have none).
+pub fn stage_usage(
+    runs: &[RunRecord],
+) -> std::collections::BTreeMap<String, Vec<(u64, ResourceUsage)>> {
+    let mut usage = std::collections::BTreeMap::<String, Vec<(u64, ResourceUsage)>>::new();
+    for s in runs.iter().flat_map(|r| &r.steps) {
+        if matches!(
+            s.status(),
+            StepStatus::Cancelled | StepStatus::Skipped | StepStatus::Blocked
+        ) {
+            continue;
+        }
+        if let Some(u) = s.resources {
+            usage
+                .entry(s.name.clone())
+                .or_default()
+                .push((s.duration_ms, u));
+        }
+    }
+    usage
+}
+
+/// Runs grouped by mode name
+pub fn by_mode(runs: &[RunRecord]) -> std::collections::BTreeMap<&str, Vec<&RunRecord>> {
+    let mut modes = std::collections::BTreeMap::<&str, Vec<&RunRecord>>::new();
+    for r in runs {
+        modes.entry(r.mode.as_str()).or_default().push(r);
+    }
+    modes
+}
+
+/// Runs grouped by what started them ("manual" unless a hook did)
+pub fn by_trigger(runs: &[RunRecord]) -> std::collections::BTreeMap<&str, Vec<&RunRecord>> {
+    let mut triggers = std::collections::BTreeMap::<&str, Vec<&RunRecord>>::new();
+    for r in runs {
+        let t = r.trigger.as_deref().unwrap_or("manual");
+        triggers.entry(t).or_default().push(r);
+    }
+    triggers
+}
+
+/// Runs of a `[projects]` root grouped by project; runs of a single
+/// project have none.
+pub fn by_project(runs: &[RunRecord]) -> std::collections::BTreeMap<&str, Vec<&RunRecord>> {
+    let mut projects = std::collections::BTreeMap::<&str, Vec<&RunRecord>>::new();
+    for r in runs {
+        if let Some(p) = r.project.as_deref() {
+            projects.entry(p).or_default().push(r);
+        }
+    }
+    projects
+}
+
+/// One line per group, when there is more than one group to compare.
+fn print_groups(title: &str, groups: &std::collections::BTreeMap<&str, Vec<&RunRecord>>) {
+    if groups.len() < 2 {
+        return;
+    }
+    println!("  {}:", title);
+    for (name, rs) in groups {
+        let ok = rs.iter().filter(|r| r.ok).count();
+        let tts: Vec<u64> = rs.iter().filter(|r| !r.aborted).map(|r| r.tts_ms).collect();
+        println!(
+            "    {:<10} runs: {} | ok: {} | TTS median: {} ms",
+            name,
+            rs.len(),
+            ok,
+            median(&tts)
+        );
+    }
 }
 
 fn avg(v: &[u64]) -> u64 {
@@ -80,6 +267,16 @@ fn median(v: &[u64]) -> u64 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn flake_rate_ignores_cached_runs() {
+        let runs = vec![
+            RunRecord::for_test("fast", &[("test", StepStatus::Flaky, 10)]),
+            RunRecord::for_test("fast", &[("test", StepStatus::Passed, 10)]),
+            RunRecord::for_test("fast", &[("test", StepStatus::Cached, 10)]),
+        ];
+        assert_eq!(flake_rates(&runs)["test"], (1, 2));
+    }
+
     #[test]
     fn median_odd() {
         let v = vec![5, 1, 9];
@@ -1,150 +1,1548 @@
-use crate::cli::Mode;
-use crate::config::Config;
-use crate::metrics::{RunRecord, StepRecord};
-use anyhow::{Context, Result};
-use std::process::Command;
-use std::time::Instant;
+use crate::cache::{self, StageCache};
+use crate::config::{self, Config};
+use crate::diagnostics;
+use crate::features;
+use crate::fix;
+use crate::junit;
+use crate::libtest;
+use crate::metrics;
+use crate::metrics::{
+    AttemptRecord, Diagnostic, FailedTest, ResourceUsage, RunRecord, StageRank, StepRecord,
+    StepStatus, SubStepRecord, TestSummary,
+};
+use crate::nextest;
+use crate::workspace;
+use anyhow::{anyhow, Context, Result};
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 #[derive(Debug)]
 struct CmdResult {
-    ok: bool,
+    status: StepStatus,
     code: Option<i32>,
     stderr: String,
+    diagnostics: Vec<Diagnostic>,
+    substeps: Vec<SubStepRecord>,
+    /// Earlier tries and this one, when the stage was retried
+    attempts: Vec<AttemptRecord>,
+    tests: Option<TestSummary>,
+    resources: Option<ResourceUsage>,
+    /// Why a stage that never ran was skipped
+    skip_reason: Option<String>,
+    started_ms: u64,
     duration_ms: u64,
 }
 
-pub fn run_pipeline(cfg: &Config, mode: Mode) -> Result<RunRecord> {
+/// A resolved pipeline stage: the command to run and how its failure is treated.
+#[derive(Debug, Clone)]
+struct Stage {
+    name: String,
+    argv: Vec<String>,
+    critical: bool,
+    /// Names of stages that must pass first
+    needs: Vec<String>,
+    /// Builds the code: when it fails, dependents never start, even when
+    /// keeping going
+    compiles: bool,
+    timeout: Option<Duration>,
+    /// Cargo writes JSON messages to stdout (see `diagnostics`)
+    json: bool,
+    /// Globs whose contents (with the command) form the cache key; empty disables caching
+    inputs: Vec<String>,
+    /// When non-empty, run these one after another instead of `argv`
+    parts: Vec<Part>,
+    /// Extra attempts after a failure, and the pause before each
+    retries: u32,
+    retry_delay: Duration,
+    /// Command that repairs what this stage checks, for `dwf fix`
+    fix: Option<Vec<String>>,
+    /// JUnit report the command writes (nextest); read instead of libtest output
+    junit: Option<PathBuf>,
+    /// Globs of which at least one changed file must match for the stage to run
+    when_changed: Vec<String>,
+    /// Directory to run in instead of the current one (the staged snapshot)
+    workdir: Option<PathBuf>,
+    /// Variables set for the command on top of dwf's own environment
+    env: Vec<(String, OsString)>,
+}
+
+/// One invocation of a split stage, e.g. a single workspace package.
+#[derive(Debug, Clone)]
+struct Part {
+    label: String,
+    argv: Vec<String>,
+}
+
+impl Stage {
+    /// Every command line the stage runs, for cache keys
+    fn command_lines(&self) -> Vec<String> {
+        if self.parts.is_empty() {
+            return self.argv.clone();
+        }
+        self.parts
+            .iter()
+            .flat_map(|p| p.argv.iter().cloned().chain(["\n".to_string()]))
+            .collect()
+    }
+}
+
+/// Per-invocation knobs that come from the command line rather than dwf.toml.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Overrides `pipeline.jobs`
+    pub jobs: Option<usize>,
+    /// Keep running stages after a critical failure, short of those needing a
+    /// stage that failed to compile (overrides `pipeline.fail_fast`)
+    pub keep_going: bool,
+    /// Echo step output to the terminal while it runs
+    pub stream: bool,
+    /// Run every stage even if its inputs are unchanged since it last passed
+    pub no_cache: bool,
+    /// Set from outside (Ctrl-C, `dwf watch` restarting) to kill running
+    /// stages; the run is then recorded as aborted
+    pub abort: Option<Arc<AtomicBool>>,
+    /// Run each stage's fixing command before the checking pipeline
+    pub fix: bool,
+    /// Limit cargo stages to crates changed since the base ref (overrides `workspace.changed_only`)
+    pub changed: bool,
+    /// Git ref to diff against (overrides `workspace.base`; implies `changed`)
+    pub base: Option<String>,
+    /// Recorded as the run's trigger, e.g. "pre-commit"
+    pub trigger: Option<String>,
+    /// Run stage commands here (the staged snapshot) instead of the current directory
+    pub workdir: Option<PathBuf>,
+    /// `workdir` holds the git index (`--staged`): changes are what is staged
+    pub staged: bool,
+    /// CARGO_TARGET_DIR for stage commands, so another checkout (staged
+    /// export, bisect worktree) builds into this workspace's target dir
+    pub target_dir: Option<PathBuf>,
+    /// Commit checked out in `workdir`, recorded instead of HEAD (`dwf bisect`)
+    pub commit: Option<String>,
+    /// Run just this stage of the mode
+    pub only: Option<String>,
+    /// Collect streamed output here instead of printing it (tests)
+    pub capture: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+/// Where a streaming stage's output goes: each line starts with `prefix`
+/// and is printed, or appended to the capture buffer.
+#[derive(Debug, Clone)]
+struct Stream {
+    prefix: String,
+    capture: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl Stream {
+    fn new(prefix: String, opts: &RunOptions) -> Self {
+        Self {
+            prefix,
+            capture: opts.capture.clone(),
+        }
+    }
+
+    /// One write per call keeps lines from parallel stages intact
+    fn write(&self, to_stderr: bool, bytes: &[u8]) {
+        let _ = match &self.capture {
+            Some(buf) => {
+                buf.lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .extend_from_slice(bytes);
+                Ok(())
+            }
+            None if to_stderr => std::io::stderr().lock().write_all(bytes),
+            None => std::io::stdout().lock().write_all(bytes),
+        };
+    }
+}
+
+/// Stop signal checked by running stages: raised by a fail-fast failure
+/// or from outside through `RunOptions::abort`.
+struct Cancel<'a> {
+    local: AtomicBool,
+    abort: Option<&'a AtomicBool>,
+}
+
+impl Cancel<'_> {
+    fn is_set(&self) -> bool {
+        self.local.load(Ordering::SeqCst) || self.abort.is_some_and(|a| a.load(Ordering::SeqCst))
+    }
+
+    fn set(&self) {
+        self.local.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn run_pipeline(cfg: &Config, mode: &str, opts: &RunOptions) -> Result<RunRecord> {
     let start = Instant::now();
     let ts = OffsetDateTime::now_utc()
         .format(&Rfc3339)
         .unwrap_or_else(|_| "unknown".to_string());
 
-    let mut steps: Vec<StepRecord> = Vec::new();
+    let Scoped {
+        mut stages,
+        packages,
+        ..
+    } = scoped_stages(cfg, mode, opts)?;
+    if let Some(only) = &opts.only {
+        // Alone, so whatever it needs counts as done
+        stages.retain(|st| &st.name == only);
+        if stages.is_empty() {
+            return Err(anyhow!("stage `{}` is not part of mode `{}`", only, mode));
+        }
+    }
+    for st in &mut stages {
+        st.workdir = opts.workdir.clone();
+        if let Some(dir) = &opts.target_dir {
+            st.env
+                .push(("CARGO_TARGET_DIR".to_string(), dir.clone().into_os_string()));
+        }
+    }
+    let (stages, order) = if cfg.pipeline.order == "adaptive" {
+        let history = metrics::load_last_runs(HISTORY_WINDOW).unwrap_or_default();
+        adaptive_order(stages, &history)
+    } else {
+        (stages, Vec::new())
+    };
+    // Taken before anything runs: later `when.changed` checks diff against it
+    let commit = match &opts.commit {
+        Some(c) => Some(c.clone()),
+        None => workspace::head_commit().ok(),
+    };
+
+    if stages.iter().any(|st| st.junit.is_some()) {
+        nextest::write_tool_config(cfg)?;
+    }
+
+    // Fixers change the inputs, so they run before any cache keys are taken
+    let fixed_files = if opts.fix {
+        Some(apply_fixes(cfg, &stages, opts, start)?)
+    } else {
+        None
+    };
+
+    let mut lookup = CacheLookup::new(cfg, opts, &stages)?;
+    let shortcuts: Vec<Option<Shortcut>> = when_skips(cfg, mode, &stages, opts)
+        .into_iter()
+        .zip(&lookup.hits)
+        .map(|(skip, hit)| match (skip, hit) {
+            (Some(reason), _) => Some(Shortcut::Skipped { reason }),
+            (None, Some(duration_ms)) => Some(Shortcut::Cached {
+                duration_ms: *duration_ms,
+            }),
+            (None, None) => None,
+        })
+        .collect();
+    let steps = execute(cfg, &stages, &shortcuts, opts, start)?;
+    lookup.record(cfg, &stages, &steps)?;
+
+    let mut run = finalize_run(ts, mode, start, steps);
+    run.packages = packages;
+    run.commit = commit;
+    run.order = order;
+    run.trigger = opts.trigger.clone();
+    if opts
+        .abort
+        .as_ref()
+        .is_some_and(|a| a.load(Ordering::SeqCst))
+    {
+        // Unfinished, so neither green nor a real failure
+        run.aborted = true;
+        run.ok = false;
+        run.ttg_ms = None;
+    }
+    if let Some(files) = fixed_files {
+        run.autofix = true;
+        run.fixed_files = files;
+    }
+    Ok(run)
+}
+
+/// Run every stage's fixing command in plan order and return the files they
+/// changed. A failing fixer does not stop the rest; the checks that follow
+/// decide whether the run is green.
+fn apply_fixes(
+    cfg: &Config,
+    stages: &[Stage],
+    opts: &RunOptions,
+    run_start: Instant,
+) -> Result<Vec<String>> {
+    // Fixers run where the checks will (`opts.workdir`)
+    let root = opts.workdir.as_deref().unwrap_or(Path::new("."));
+    let before = fix::snapshot(cfg, root);
+    let cancel = Cancel {
+        local: AtomicBool::new(false),
+        abort: opts.abort.as_deref(),
+    };
+    for st in stages {
+        let Some(fix) = &st.fix else { continue };
+        if cancel.is_set() {
+            break;
+        }
+        let fixer = Stage {
+            json: false,
+            ..st.clone()
+        };
+        let echo = opts.stream.then(|| Stream::new(String::new(), opts));
+        let r = run_cmd(cfg, &fixer, fix, st.timeout, echo, &cancel, run_start)?;
+        if !r.status.is_success() {
+            eprintln!(
+                "dwf: fixing {} failed ({}, exit={:?})",
+                st.name,
+                fix.join(" "),
+                r.code
+            );
+            if !opts.stream && !r.stderr.trim().is_empty() {
+                eprintln!("{}", r.stderr.trim_end());
+            }
+        }
+    }
+    Ok(fix::changed_files(&before, &fix::snapshot(cfg, root)))
+}
+
+/// The mode's stages after limiting them to changed packages.
+struct Scoped {
+    stages: Vec<Stage>,
+    /// Packages the run is limited to (None = whole workspace)
+    packages: Option<Vec<String>>,
+    /// Stages left with no package to check
+    dropped: Vec<String>,
+}
+
+fn scoped_stages(cfg: &Config, mode: &str, opts: &RunOptions) -> Result<Scoped> {
+    let stages = plan_stages(cfg, mode)?;
+    if !(opts.changed || opts.base.is_some() || cfg.workspace.changed_only) {
+        return Ok(Scoped {
+            stages: expand_feature_matrix(cfg, mode, stages)?,
+            packages: None,
+            dropped: Vec::new(),
+        });
+    }
+    let base = opts.base.as_deref().unwrap_or(&cfg.workspace.base);
+    let packages = workspace::changed_packages(base, opts.staged)?;
+    let names: Vec<String> = stages.iter().map(|st| st.name.clone()).collect();
+    let stages = scope_to_packages(stages, &packages);
+    let dropped = names
+        .into_iter()
+        .filter(|n| !stages.iter().any(|st| &st.name == n))
+        .collect();
+    Ok(Scoped {
+        stages: expand_feature_matrix(cfg, mode, stages)?,
+        packages: Some(packages),
+        dropped,
+    })
+}
+
+/// Run the stages named in `[pipeline.feature_matrix]` once per package and
+/// feature combination, each invocation a part of its own.
+fn expand_feature_matrix(cfg: &Config, mode: &str, stages: Vec<Stage>) -> Result<Vec<Stage>> {
+    let Some(m) = &cfg.pipeline.feature_matrix else {
+        return Ok(stages);
+    };
+    let in_matrix =
+        |st: &Stage| m.stages.contains(&st.name) && workspace::accepts_package(&st.argv);
+    if !m.modes.iter().any(|x| x == mode) || !stages.iter().any(in_matrix) {
+        return Ok(stages);
+    }
+    let (_root, members) = workspace::load_members()?;
+    Ok(stages
+        .into_iter()
+        .map(|st| {
+            if in_matrix(&st) {
+                with_feature_parts(m, st, &members)
+            } else {
+                st
+            }
+        })
+        .collect())
+}
+
+fn with_feature_parts(
+    m: &config::FeatureMatrixConfig,
+    mut st: Stage,
+    members: &[workspace::Member],
+) -> Stage {
+    // `all` is a combination of its own now
+    st.argv.retain(|a| a != "--all-features");
+    // (package, command for it); a lone package needs no `-p`
+    let targets: Vec<(Option<String>, Vec<String>)> = if !st.parts.is_empty() {
+        st.parts
+            .iter()
+            .map(|p| (Some(p.label.clone()), p.argv.clone()))
+            .collect()
+    } else if members.len() == 1 {
+        vec![(None, st.argv.clone())]
+    } else {
+        members
+            .iter()
+            .map(|mb| {
+                (
+                    Some(mb.name.clone()),
+                    workspace::with_package(&st.argv, &mb.name),
+                )
+            })
+            .collect()
+    };
+
+    st.parts = targets
+        .into_iter()
+        .flat_map(|(package, argv)| {
+            let member = match &package {
+                Some(p) => members.iter().find(|mb| &mb.name == p),
+                None => members.first(),
+            };
+            let available = member.map_or(&[][..], |mb| &mb.features[..]);
+            features::combinations(m, available)
+                .into_iter()
+                .map(move |c| {
+                    let mut argv: Vec<String> = argv
+                        .iter()
+                        .filter(|a| *a != "--all-features")
+                        .cloned()
+                        .collect();
+                    splice_args(&mut argv, &c.args);
+                    Part {
+                        label: match &package {
+                            Some(p) => format!("{}/{}", p, c.label),
+                            None => c.label,
+                        },
+                        argv,
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    st
+}
 
-    // 1) fmt
-    let r = run_cmd(cfg, "fmt", cargo_fmt_cmd())?;
-    steps.push(step_record("fmt", &r));
-    if !r.ok {
-        return Ok(finalize_run(ts, mode, start, steps));
+/// Cache keys for the planned stages and which of them are hits.
+struct CacheLookup {
+    enabled: bool,
+    cache: StageCache,
+    keys: Vec<Option<String>>,
+    /// Duration of the green run a hit stands in for
+    hits: Vec<Option<u64>>,
+}
+
+impl CacheLookup {
+    fn new(cfg: &Config, opts: &RunOptions, stages: &[Stage]) -> Result<Self> {
+        // Cache keys hash the working tree, which a staged run does not check
+        let enabled = cfg.pipeline.cache
+            && !opts.no_cache
+            && opts.workdir.is_none()
+            && stages.iter().any(|s| !s.inputs.is_empty());
+        let mut cache = StageCache::default();
+        let mut keys: Vec<Option<String>> = vec![None; stages.len()];
+        if enabled {
+            cache = StageCache::load(cfg);
+            // RUSTFLAGS and friends change the build as much as the toolchain
+            let toolchain = cache::toolchain_version() + &cache::build_env(std::env::vars());
+            for (key, st) in keys.iter_mut().zip(stages) {
+                if !st.inputs.is_empty() {
+                    *key = Some(cache::stage_key(
+                        cfg,
+                        &st.command_lines(),
+                        &st.inputs,
+                        &toolchain,
+                    )?);
+                }
+            }
+        }
+        let hits = stages
+            .iter()
+            .zip(&keys)
+            .map(|(st, key)| key.as_ref().and_then(|k| cache.hit(&st.name, k)))
+            .collect();
+        Ok(Self {
+            enabled,
+            cache,
+            keys,
+            hits,
+        })
     }
 
-    // 2) check
-    let r = run_cmd(cfg, "check", cargo_check_cmd())?;
-    steps.push(step_record("check", &r));
-    if !r.ok {
-        return Ok(finalize_run(ts, mode, start, steps));
+    /// Remember stages that passed, and save the cache.
+    fn record(&mut self, cfg: &Config, stages: &[Stage], steps: &[StepRecord]) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        for (st, key) in stages.iter().zip(&self.keys) {
+            let Some(key) = key else { continue };
+            let passed = steps
+                .iter()
+                .find(|s| s.name == st.name && s.status() == StepStatus::Passed);
+            if let Some(step) = passed {
+                self.cache.record(&st.name, key, step.duration_ms);
+            }
+        }
+        self.cache.save(cfg)
+    }
+}
+
+/// Why each stage with `when.changed` globs can be skipped: nothing that
+/// changed matches them. With nothing to compare against (no green run
+/// yet, or git failing) every stage runs.
+fn when_skips(
+    cfg: &Config,
+    mode: &str,
+    stages: &[Stage],
+    opts: &RunOptions,
+) -> Vec<Option<String>> {
+    // A run pinned to a commit checks that commit, not what changed
+    if opts.commit.is_some() || stages.iter().all(|st| st.when_changed.is_empty()) {
+        return vec![None; stages.len()];
+    }
+    match changed_since(cfg, mode, opts) {
+        Ok(Some(changed)) => unchanged_stages(stages, &changed),
+        Ok(None) => vec![None; stages.len()],
+        Err(e) => {
+            eprintln!("dwf: {:#}; running stages with when.changed anyway", e);
+            vec![None; stages.len()]
+        }
     }
+}
+
+/// Files changed since the base ref (when limiting to changed crates) or
+/// since the commit of the last green run of `mode`, relative to the
+/// current directory. None when there is no such run.
+fn changed_since(cfg: &Config, mode: &str, opts: &RunOptions) -> Result<Option<Vec<PathBuf>>> {
+    let base = if opts.changed || opts.base.is_some() || cfg.workspace.changed_only {
+        opts.base
+            .clone()
+            .unwrap_or_else(|| cfg.workspace.base.clone())
+    } else {
+        let runs = metrics::load_last_runs(GREEN_RUN_WINDOW).unwrap_or_default();
+        let green = runs
+            .iter()
+            .rev()
+            .filter(|r| r.mode == mode && r.ok && !r.aborted)
+            .find_map(|r| r.commit.clone());
+        match green {
+            Some(commit) => commit,
+            None => return Ok(None),
+        }
+    };
+    let cwd = std::env::current_dir()?.canonicalize()?;
+    let changed = workspace::changed_files(&base, opts.staged)?;
+    Ok(Some(
+        changed
+            .iter()
+            .filter_map(|p| p.strip_prefix(&cwd).ok().map(PathBuf::from))
+            .collect(),
+    ))
+}
+
+/// Runs searched for the last green commit
+const GREEN_RUN_WINDOW: usize = 200;
+
+/// A glob without `/` (`*.md`) matches a file name at any depth, as in
+/// .gitignore; others match the whole path.
+fn unchanged_stages(stages: &[Stage], changed: &[PathBuf]) -> Vec<Option<String>> {
+    let opts = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    stages
+        .iter()
+        .map(|st| {
+            if st.when_changed.is_empty() {
+                return None;
+            }
+            let relevant = st
+                .when_changed
+                .iter()
+                .filter_map(|g| Some((glob::Pattern::new(g).ok()?, !g.contains('/'))))
+                .any(|(p, basename)| {
+                    changed.iter().any(|f| match f.file_name() {
+                        Some(name) if basename => p.matches_with(&name.to_string_lossy(), opts),
+                        _ => p.matches_path_with(f, opts),
+                    })
+                });
+            (!relevant).then(|| "no relevant changes".to_string())
+        })
+        .collect()
+}
+
+/// Split package-aware cargo stages into one `-p <pkg>` invocation per
+/// package; with no packages to check they are dropped entirely.
+fn scope_to_packages(stages: Vec<Stage>, packages: &[String]) -> Vec<Stage> {
+    stages
+        .into_iter()
+        .filter_map(|mut st| {
+            if !workspace::accepts_package(&st.argv) {
+                return Some(st);
+            }
+            if packages.is_empty() {
+                return None;
+            }
+            st.parts = packages
+                .iter()
+                .map(|p| Part {
+                    label: p.clone(),
+                    argv: workspace::with_package(&st.argv, p),
+                })
+                .collect();
+            Some(st)
+        })
+        .collect()
+}
+
+fn effective_jobs(cfg: &Config, opts: &RunOptions) -> usize {
+    opts.jobs
+        .or(cfg.pipeline.jobs)
+        .unwrap_or_else(default_jobs)
+        .max(1)
+}
+
+fn default_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
 
-    // 3) clippy
-    let r = run_cmd(cfg, "clippy", cargo_clippy_cmd(cfg, mode))?;
-    steps.push(step_record("clippy", &r));
-    if !r.ok {
-        return Ok(finalize_run(ts, mode, start, steps));
+/// A stage that completes without running.
+#[derive(Debug, Clone)]
+enum Shortcut {
+    /// Cache hit standing in for a green run that took `duration_ms`
+    Cached { duration_ms: u64 },
+    /// `when` conditions not met
+    Skipped { reason: String },
+}
+
+/// Run `stages` as a dependency graph, at most `jobs` at a time.
+///
+/// A stage starts once everything it `needs` has passed; stages whose
+/// dependencies failed never start and are recorded as blocked. When not
+/// failing fast, a finished dependency is enough unless it failed to
+/// compile, so every stage that can runs and reports its own problems.
+/// Stages with a shortcut (cache hit or unmet `when`) are recorded as cached
+/// or skipped instead of running. When
+/// failing fast, a critical failure stops new stages from starting and kills
+/// running siblings (recorded as cancelled); otherwise every runnable stage
+/// still runs.
+fn execute(
+    cfg: &Config,
+    stages: &[Stage],
+    shortcuts: &[Option<Shortcut>],
+    opts: &RunOptions,
+    run_start: Instant,
+) -> Result<Vec<StepRecord>> {
+    let jobs = effective_jobs(cfg, opts);
+    let fail_fast = cfg.pipeline.fail_fast && !opts.keep_going;
+    // Tag streamed lines with the stage name once stages can interleave
+    let prefix = opts.stream && jobs > 1 && stages.len() > 1;
+
+    let cancel = Cancel {
+        local: AtomicBool::new(false),
+        abort: opts.abort.as_deref(),
+    };
+    let (tx, rx) = mpsc::channel::<(usize, Result<CmdResult>)>();
+
+    let mut results: Vec<Option<CmdResult>> = stages.iter().map(|_| None).collect();
+    let mut started = vec![false; stages.len()];
+    let mut first_err: Option<anyhow::Error> = None;
+
+    std::thread::scope(|scope| {
+        let mut running = 0usize;
+        loop {
+            // Shortcuts complete instantly and may unblock later stages, so
+            // keep scanning until nothing more can start
+            let mut progressed = true;
+            while progressed && !cancel.is_set() {
+                progressed = false;
+                for (i, stage) in stages.iter().enumerate() {
+                    if running >= jobs {
+                        break;
+                    }
+                    if started[i] || !deps_passed(stages, &results, stage, !fail_fast) {
+                        continue;
+                    }
+                    started[i] = true;
+                    progressed = true;
+                    if let Some(sc) = &shortcuts[i] {
+                        results[i] = Some(shortcut_result(run_start, sc));
+                        continue;
+                    }
+                    running += 1;
+                    let tx = tx.clone();
+                    let cancel = &cancel;
+                    scope.spawn(move || {
+                        let echo = opts.stream.then(|| {
+                            let tag = if prefix {
+                                format!("[{}] ", stage.name)
+                            } else {
+                                String::new()
+                            };
+                            Stream::new(tag, opts)
+                        });
+                        let r = run_with_retries(cfg, stage, fail_fast, echo, cancel, run_start);
+                        let _ = tx.send((i, r));
+                    });
+                }
+            }
+            if running == 0 {
+                break;
+            }
+
+            let Ok((i, r)) = rx.recv() else { break };
+            running -= 1;
+            match r {
+                Ok(r) => {
+                    if fail_fast && r.status.is_failure() && stages[i].critical {
+                        cancel.set();
+                    }
+                    results[i] = Some(r);
+                }
+                Err(e) => {
+                    cancel.set();
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+    });
+
+    if let Some(e) = first_err {
+        return Err(e);
     }
 
-    // 4) test (only in full)
-    if mode == Mode::Full {
-        let r = run_cmd(cfg, "test", cargo_test_cmd(cfg, mode))?;
-        steps.push(step_record("test", &r));
-        if !r.ok {
-            return Ok(finalize_run(ts, mode, start, steps));
+    // Record what never started because a dependency failed, following
+    // chains of blocked stages
+    let mut progressed = true;
+    while progressed {
+        progressed = false;
+        for (i, stage) in stages.iter().enumerate() {
+            if results[i].is_some() {
+                continue;
+            }
+            if let Some(reason) = blocked_by(stages, &results, stage) {
+                results[i] = Some(CmdResult {
+                    status: StepStatus::Blocked,
+                    ..shortcut_result(run_start, &Shortcut::Skipped { reason })
+                });
+                progressed = true;
+            }
         }
     }
 
-    Ok(finalize_run(ts, mode, start, steps))
+    // Keep plan order in the record, independent of completion order
+    Ok(stages
+        .iter()
+        .zip(results.iter())
+        .filter_map(|(stage, r)| r.as_ref().map(|r| step_record(stage, r)))
+        .collect())
 }
 
-fn finalize_run(ts: String, mode: Mode, start: Instant, steps: Vec<StepRecord>) -> RunRecord {
-    let total_ms = start.elapsed().as_millis() as u64;
+fn shortcut_result(run_start: Instant, shortcut: &Shortcut) -> CmdResult {
+    let (status, duration_ms, skip_reason) = match shortcut {
+        Shortcut::Cached { duration_ms } => (StepStatus::Cached, *duration_ms, None),
+        Shortcut::Skipped { reason } => (StepStatus::Skipped, 0, Some(reason.clone())),
+    };
+    CmdResult {
+        status,
+        code: None,
+        stderr: String::new(),
+        diagnostics: Vec::new(),
+        substeps: Vec::new(),
+        attempts: Vec::new(),
+        tests: None,
+        resources: None,
+        skip_reason,
+        started_ms: run_start.elapsed().as_millis() as u64,
+        duration_ms,
+    }
+}
 
-    // TTS: time until first failing step, else total
-    let mut tts_ms = total_ms;
-    let mut acc = 0u64;
-    for s in &steps {
-        acc = acc.saturating_add(s.duration_ms);
-        if !s.ok {
-            tts_ms = acc;
-            break;
+/// Whether `stage` may start; with `keep_going`, failed dependencies only
+/// order it after them, unless they failed to compile.
+fn deps_passed(
+    stages: &[Stage],
+    results: &[Option<CmdResult>],
+    stage: &Stage,
+    keep_going: bool,
+) -> bool {
+    stage.needs.iter().all(|dep| {
+        match stages.iter().position(|s| &s.name == dep) {
+            Some(j) => matches!(
+                &results[j],
+                Some(r) if r.status.is_success()
+                    || (keep_going && r.status.is_failure() && !stages[j].compiles)
+            ),
+            // Dependency not part of this mode: nothing to wait for
+            None => true,
         }
+    })
+}
+
+/// Why `stage` cannot run: the first dependency that failed, or the reason
+/// a blocked dependency carries. Stages cancelled by a failure elsewhere or
+/// an interrupt do not count.
+fn blocked_by(stages: &[Stage], results: &[Option<CmdResult>], stage: &Stage) -> Option<String> {
+    stage.needs.iter().find_map(|dep| {
+        let j = stages.iter().position(|s| &s.name == dep)?;
+        match &results[j] {
+            Some(r) if r.status.is_failure() => Some(format!("dependency failed: {}", dep)),
+            Some(r) if r.status == StepStatus::Blocked => r.skip_reason.clone(),
+            _ => None,
+        }
+    })
+}
+
+/// Stages for `mode`: the `[[pipeline.stage]]` tables in declaration order,
+/// or the built-in fmt → check → clippy → test steps when none are declared.
+/// `fast` and `full` are built in; other modes come from `[modes.<name>]`.
+fn plan_stages(cfg: &Config, mode: &str) -> Result<Vec<Stage>> {
+    let custom = cfg.modes.get(mode);
+    if custom.is_none() && mode != "fast" && mode != "full" {
+        return Err(anyhow!(
+            "unknown mode `{}` (available: {})",
+            mode,
+            config::mode_names(cfg).join(", ")
+        ));
     }
 
-    let ok = steps.iter().all(|s| s.ok);
+    let builtin = cfg.pipeline.stages.is_empty();
+    let mut stages = if builtin {
+        default_stages(cfg, mode)
+    } else {
+        declared_stages(cfg, mode)
+    };
+
+    if let Some(m) = custom {
+        for st in &mut stages {
+            if let Some(extra) = m.args.get(&st.name) {
+                splice_args(&mut st.argv, extra);
+            }
+        }
+    }
+
+    // Declared commands run exactly as written
+    if cfg.pipeline.json_diagnostics && builtin {
+        for st in &mut stages {
+            if diagnostics::is_json_capable(&st.argv) {
+                st.argv = diagnostics::with_json_messages(std::mem::take(&mut st.argv));
+                st.json = true;
+            }
+        }
+    }
+    Ok(stages)
+}
+
+/// Add `extra` to the tool's own arguments, not to whatever follows `--`.
+fn splice_args(argv: &mut Vec<String>, extra: &[String]) {
+    let at = argv.iter().position(|a| a == "--").unwrap_or(argv.len());
+    argv.splice(at..at, extra.iter().cloned());
+}
+
+/// Whether a stage belongs to `mode`. `stage_mode` is the stage's own
+/// `mode` setting, which only matters for the built-in fast/full modes.
+fn in_mode(cfg: &Config, mode: &str, stage: &str, stage_mode: Option<&str>) -> bool {
+    match cfg.modes.get(mode) {
+        Some(m) => m.stages.iter().any(|s| s == stage),
+        None if mode == "full" => true,
+        None => stage_mode != Some("full"),
+    }
+}
+
+fn declared_stages(cfg: &Config, mode: &str) -> Vec<Stage> {
+    let mut stages: Vec<Stage> = Vec::new();
+    for s in &cfg.pipeline.stages {
+        if !in_mode(cfg, mode, &s.name, s.mode.as_deref()) {
+            continue;
+        }
+        // Without `needs`, a stage waits for the one declared before it
+        let needs = match &s.needs {
+            Some(needs) => needs.clone(),
+            None => stages.last().map(|p| p.name.clone()).into_iter().collect(),
+        };
+        stages.push(Stage {
+            name: s.name.clone(),
+            argv: s.cmd.clone(),
+            critical: s.critical,
+            needs,
+            compiles: matches!(workspace::cargo_subcommand(&s.cmd), Some("check" | "build")),
+            timeout: s
+                .timeout_secs
+                .or(cfg.pipeline.timeout_secs)
+                .map(Duration::from_secs),
+            json: false,
+            inputs: s.inputs.clone(),
+            parts: Vec::new(),
+            retries: s.retries,
+            retry_delay: Duration::from_millis(s.retry_delay_ms),
+            fix: s.fix.clone(),
+            junit: None,
+            when_changed: s.when.iter().flat_map(|w| w.changed.clone()).collect(),
+            workdir: None,
+            env: Vec::new(),
+        });
+    }
+    stages
+}
+
+fn default_stages(cfg: &Config, mode: &str) -> Vec<Stage> {
+    // fmt and check are independent; clippy and test build on a clean check
+    let mut stages = vec![
+        Stage {
+            fix: Some(cargo_fmt_fix_cmd()),
+            ..builtin(cfg, "fmt", cargo_fmt_cmd(), &[])
+        },
+        builtin(cfg, "check", cargo_check_cmd(), &[]),
+        Stage {
+            fix: Some(cargo_clippy_fix_cmd(cfg, mode)),
+            ..builtin(cfg, "clippy", cargo_clippy_cmd(cfg, mode), &["check"])
+        },
+    ];
+    stages.retain(|st| in_mode(cfg, mode, &st.name, None));
+    // test (only in full); decided before probing for nextest
+    if in_mode(cfg, mode, "test", Some("full")) {
+        stages.push(test_stage(cfg, mode));
+    }
+    stages
+}
+
+/// The built-in test stage, on cargo-nextest when configured and installed.
+fn test_stage(cfg: &Config, mode: &str) -> Stage {
+    let stage = Stage {
+        inputs: cfg.pipeline.test_inputs.clone(),
+        ..builtin(cfg, "test", cargo_test_cmd(cfg, mode), &["clippy"])
+    };
+    if cfg.pipeline.test_runner != "nextest" {
+        return stage;
+    }
+    if !nextest::installed() {
+        eprintln!("dwf: cargo-nextest is not installed; running tests with cargo test");
+        return stage;
+    }
+    match nextest::junit_path() {
+        Ok(path) => Stage {
+            argv: cargo_nextest_cmd(cfg, mode),
+            junit: Some(path),
+            ..stage
+        },
+        Err(e) => {
+            eprintln!("dwf: {:#}; running tests with cargo test", e);
+            stage
+        }
+    }
+}
+
+fn builtin(cfg: &Config, name: &str, argv: Vec<String>, needs: &[&str]) -> Stage {
+    Stage {
+        name: name.to_string(),
+        argv,
+        critical: true,
+        needs: needs.iter().map(|n| n.to_string()).collect(),
+        compiles: name == "check",
+        timeout: cfg.pipeline.timeout_secs.map(Duration::from_secs),
+        json: false,
+        inputs: cache::DEFAULT_INPUTS
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+        parts: Vec::new(),
+        retries: cfg.pipeline.retries,
+        retry_delay: Duration::from_millis(cfg.pipeline.retry_delay_ms),
+        fix: None,
+        junit: None,
+        when_changed: Vec::new(),
+        workdir: None,
+        env: Vec::new(),
+    }
+}
+
+fn finalize_run(ts: String, mode: &str, start: Instant, steps: Vec<StepRecord>) -> RunRecord {
+    let total_ms = start.elapsed().as_millis() as u64;
+
+    // A failing critical step is the signal; cancelled siblings are only fallout
+    let first_failure = steps
+        .iter()
+        .filter(|s| s.critical && s.status().is_failure())
+        .min_by_key(|s| s.started_ms.saturating_add(s.duration_ms));
+
+    // TTS: time until first failing critical step, else total
+    let tts_ms = first_failure
+        .map(|s| s.started_ms.saturating_add(s.duration_ms))
+        .unwrap_or(total_ms);
+
+    // Stages blocked by a failure are not failures of their own
+    let ok = steps
+        .iter()
+        .all(|s| s.ok || !s.critical || s.status() == StepStatus::Blocked);
     let ttg_ms = if ok { Some(total_ms) } else { None };
-    let failure_stage = steps.iter().find(|s| !s.ok).map(|s| s.name.clone());
+    let failure_stage = first_failure.map(|s| s.name.clone());
+    let failure_stages = steps
+        .iter()
+        .filter(|s| s.status().is_failure())
+        .map(|s| s.name.clone())
+        .collect();
 
     RunRecord {
         timestamp_rfc3339: ts,
-        mode,
+        mode: mode.to_string(),
         ok,
         tts_ms,
         ttg_ms,
         total_ms,
         steps,
         failure_stage,
+        failure_stages,
+        packages: None,
+        autofix: false,
+        fixed_files: Vec::new(),
+        aborted: false,
+        order: Vec::new(),
+        commit: None,
+        tree: None,
+        project: None,
+        trigger: None,
     }
 }
 
-fn step_record(name: &str, r: &CmdResult) -> StepRecord {
+fn step_record(stage: &Stage, r: &CmdResult) -> StepRecord {
     StepRecord {
-        name: name.to_string(),
-        ok: r.ok,
+        name: stage.name.clone(),
+        ok: r.status.is_success(),
+        exit_code: r.code,
+        duration_ms: r.duration_ms,
+        stderr_excerpt: r.stderr.clone(),
+        critical: stage.critical,
+        status: Some(r.status),
+        started_ms: r.started_ms,
+        diagnostics: r.diagnostics.clone(),
+        substeps: r.substeps.clone(),
+        attempts: r.attempts.clone(),
+        tests: r.tests.clone(),
+        resources: r.resources,
+        skip_reason: r.skip_reason.clone(),
+    }
+}
+
+/// `run_stage`, repeated after failures up to `stage.retries` times. A pass
+/// on a later attempt is reported as flaky; the result spans every attempt.
+fn run_with_retries(
+    cfg: &Config,
+    stage: &Stage,
+    fail_fast: bool,
+    echo: Option<Stream>,
+    cancel: &Cancel,
+    run_start: Instant,
+) -> Result<CmdResult> {
+    let mut r = run_stage(cfg, stage, fail_fast, echo.clone(), cancel, run_start)?;
+    if stage.retries == 0 {
+        return Ok(r);
+    }
+
+    let started_ms = r.started_ms;
+    let mut resources = r.resources;
+    let mut attempts = Vec::new();
+    for _ in 0..stage.retries {
+        if !r.status.is_failure() {
+            break;
+        }
+        attempts.push(attempt_record(&r));
+        let wait = Instant::now();
+        while wait.elapsed() < stage.retry_delay && !cancel.is_set() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if cancel.is_set() {
+            break;
+        }
+        if let Some(stream) = &echo {
+            let note = format!(
+                "{}dwf: retrying {} (attempt {})\n",
+                stream.prefix,
+                stage.name,
+                attempts.len() + 1
+            );
+            stream.write(true, note.as_bytes());
+        }
+        r = run_stage(cfg, stage, fail_fast, echo.clone(), cancel, run_start)?;
+        add_usage(&mut resources, r.resources);
+    }
+    if attempts.is_empty() {
+        return Ok(r);
+    }
+
+    attempts.push(attempt_record(&r));
+    if r.status == StepStatus::Passed {
+        r.status = StepStatus::Flaky;
+    }
+    let end_ms = r.started_ms + r.duration_ms;
+    r.started_ms = started_ms;
+    r.duration_ms = end_ms - started_ms;
+    r.attempts = attempts;
+    r.resources = resources;
+    Ok(r)
+}
+
+fn add_usage(total: &mut Option<ResourceUsage>, other: Option<ResourceUsage>) {
+    match (total.as_mut(), other) {
+        (Some(t), Some(o)) => t.add(&o),
+        (None, o) => *total = o,
+        (Some(_), None) => {}
+    }
+}
+
+fn attempt_record(r: &CmdResult) -> AttemptRecord {
+    AttemptRecord {
+        status: r.status,
         exit_code: r.code,
         duration_ms: r.duration_ms,
         stderr_excerpt: r.stderr.clone(),
     }
 }
 
-fn run_cmd(cfg: &Config, step_name: &str, mut cmd: Command) -> Result<CmdResult> {
+/// Run one stage: its command, or each of its parts in turn. Parts share
+/// the stage's time limit; with `fail_fast` the first failing part ends it.
+fn run_stage(
+    cfg: &Config,
+    stage: &Stage,
+    fail_fast: bool,
+    echo: Option<Stream>,
+    cancel: &Cancel,
+    run_start: Instant,
+) -> Result<CmdResult> {
+    if stage.parts.is_empty() {
+        return run_cmd(
+            cfg,
+            stage,
+            &stage.argv,
+            stage.timeout,
+            echo,
+            cancel,
+            run_start,
+        );
+    }
+
+    let t0 = Instant::now();
+    let mut total = CmdResult {
+        status: StepStatus::Passed,
+        code: Some(0),
+        stderr: String::new(),
+        diagnostics: Vec::new(),
+        substeps: Vec::new(),
+        attempts: Vec::new(),
+        tests: None,
+        resources: None,
+        skip_reason: None,
+        started_ms: t0.duration_since(run_start).as_millis() as u64,
+        duration_ms: 0,
+    };
+    for part in &stage.parts {
+        let remaining = stage.timeout.map(|t| t.saturating_sub(t0.elapsed()));
+        let r = run_cmd(
+            cfg,
+            stage,
+            &part.argv,
+            remaining,
+            echo.clone(),
+            cancel,
+            run_start,
+        )?;
+        total.substeps.push(SubStepRecord {
+            name: part.label.clone(),
+            ok: r.status.is_success(),
+            exit_code: r.code,
+            duration_ms: r.duration_ms,
+        });
+        libtest::merge(&mut total.tests, r.tests);
+        add_usage(&mut total.resources, r.resources);
+        for d in r.diagnostics {
+            if !total.diagnostics.contains(&d) {
+                total.diagnostics.push(d);
+            }
+        }
+        if r.status.is_success() {
+            continue;
+        }
+        if total.status.is_success() {
+            total.status = r.status;
+            total.code = r.code;
+        }
+        if !r.stderr.trim().is_empty() {
+            total
+                .stderr
+                .push_str(&format!("[{}]\n{}", part.label, r.stderr));
+        }
+        // Timeouts and cancellation end the whole stage
+        if fail_fast || r.status != StepStatus::Failed {
+            break;
+        }
+    }
+    total.duration_ms = t0.elapsed().as_millis() as u64;
+    total.stderr = trim_lines(&total.stderr, cfg.pipeline.stderr_max_lines);
+    Ok(total)
+}
+
+/// Run one command for `stage`. With `echo` set, output is also copied line
+/// by line to the terminal, each line starting with the given prefix.
+fn run_cmd(
+    cfg: &Config,
+    stage: &Stage,
+    argv: &[String],
+    timeout: Option<Duration>,
+    echo: Option<Stream>,
+    cancel: &Cancel,
+    run_start: Instant,
+) -> Result<CmdResult> {
+    let step_name = stage.name.as_str();
+    let (program, args) = argv
+        .split_first()
+        .with_context(|| format!("step `{}` has an empty command", step_name))?;
+    let mut cmd = Command::new(program);
+    if let Some(dir) = &stage.workdir {
+        cmd.current_dir(dir);
+    }
+    cmd.envs(stage.env.iter().map(|(k, v)| (k, v)));
+    cmd.args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Own process group, so a timeout or cancel can take down the whole tree
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+    // A report left over from an earlier run must not pass for this one
+    if let Some(path) = &stage.junit {
+        let _ = std::fs::remove_file(path);
+    }
+
     let t0 = Instant::now();
-    let out = cmd
-        .output()
+    let started_ms = t0.duration_since(run_start).as_millis() as u64;
+    let mut child = cmd
+        .spawn()
         .with_context(|| format!("failed to execute step `{}`", step_name))?;
+
+    // Drain both pipes on their own threads so a chatty child never blocks
+    let stdout_echo = if stage.json {
+        Echo::CargoJson
+    } else {
+        Echo::Stdout
+    };
+    let stdout = child
+        .stdout
+        .take()
+        .map(|r| drain(r, echo.clone().map(|p| (p, stdout_echo))));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|r| drain(r, echo.clone().map(|p| (p, Echo::Stderr))));
+
+    let mut killed: Option<StepStatus> = None;
+    let (exit, resources) = loop {
+        if let Some(done) =
+            reap(&mut child, false).with_context(|| format!("wait for step `{}`", step_name))?
+        {
+            break done;
+        }
+        if cancel.is_set() {
+            killed = Some(StepStatus::Cancelled);
+        } else if timeout.is_some_and(|t| t0.elapsed() >= t) {
+            killed = Some(StepStatus::TimedOut);
+        }
+        if killed.is_some() {
+            kill_tree(&mut child);
+            let done =
+                reap(&mut child, true).with_context(|| format!("wait for step `{}`", step_name))?;
+            break done.context("child vanished")?;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
     let duration_ms = t0.elapsed().as_millis() as u64;
 
-    let code = out.status.code();
-    let ok = out.status.success();
+    // Whatever was written before a kill is still in the pipes. A process
+    // that left the group (a daemon, `setsid`) can keep them open for good,
+    // so stop reading after a grace period
+    let deadline = Instant::now() + DRAIN_GRACE;
+    let (stdout_raw, stdout_cut) = stdout.map(|d| d.collect(deadline)).unwrap_or_default();
+    let stdout_raw = String::from_utf8_lossy(&stdout_raw).to_string();
+    let diagnostics = if stage.json {
+        diagnostics::parse_messages(&stdout_raw)
+    } else {
+        Vec::new()
+    };
+    let (stderr_raw, stderr_cut) = stderr.map(|d| d.collect(deadline)).unwrap_or_default();
+    let mut stderr_raw = String::from_utf8_lossy(&stderr_raw).to_string();
+    if stdout_cut || stderr_cut {
+        stderr_raw.push_str(
+            "dwf: stopped reading output: a process the stage started still holds it open\n",
+        );
+    }
+    if killed == Some(StepStatus::TimedOut) {
+        if let Some(t) = stage.timeout {
+            stderr_raw.push_str(&format!("dwf: timed out after {}s\n", t.as_secs()));
+        }
+    }
 
-    let stderr_raw = String::from_utf8_lossy(&out.stderr).to_string();
+    let status = match killed {
+        Some(st) => st,
+        None if exit.success() => StepStatus::Passed,
+        None => StepStatus::Failed,
+    };
+
+    let tests = match &stage.junit {
+        Some(path) => match junit::read(path) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                let note = format!("dwf: no per-test results: {:#}", e);
+                // A passing stage's stderr is never shown
+                if status.is_success() {
+                    eprintln!("{}", note);
+                }
+                stderr_raw.push_str(&note);
+                stderr_raw.push('\n');
+                None
+            }
+        },
+        None if runs_libtest(stage, argv) => libtest::parse(&stdout_raw),
+        None => None,
+    };
     let stderr = trim_lines(&stderr_raw, cfg.pipeline.stderr_max_lines);
 
     Ok(CmdResult {
-        ok,
-        code,
+        status,
+        code: exit.code(),
         stderr,
+        diagnostics,
+        substeps: Vec::new(),
+        attempts: Vec::new(),
+        resources,
+        tests,
+        skip_reason: None,
+        started_ms,
         duration_ms,
     })
 }
 
-fn cargo_fmt_cmd() -> Command {
-    let mut c = Command::new("cargo");
-    c.arg("fmt").arg("--all").arg("--check");
-    c
+/// Whether `argv` runs libtest, whose summary lines can be parsed: the
+/// test stage, or `cargo test` under any stage name. Other tools may print
+/// lines that look alike.
+fn runs_libtest(stage: &Stage, argv: &[String]) -> bool {
+    stage.name == "test" || workspace::cargo_subcommand(argv) == Some("test")
 }
 
-fn cargo_check_cmd() -> Command {
-    let mut c = Command::new("cargo");
-    c.arg("check").arg("-q");
-    c
+#[cfg(unix)]
+fn kill_tree(child: &mut std::process::Child) {
+    // The child leads its own process group, so this also reaches rustc,
+    // test binaries and anything else it spawned.
+    // SAFETY: kill takes no pointers; the child is not reaped yet, so its
+    // pid (and the group id) cannot have been reused
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_tree(child: &mut std::process::Child) {
+    let _ = child.kill();
 }
 
-fn cargo_clippy_cmd(cfg: &Config, mode: Mode) -> Command {
-    let mut c = Command::new("cargo");
-    c.arg("clippy").arg("--all-targets");
-    if mode == Mode::Full && cfg.pipeline.all_features_in_full {
-        c.arg("--all-features");
+/// Exit status and resource usage once the child has exited; `block` waits
+/// for it. Uses `wait4` so usage comes with the status in one call.
+#[cfg(unix)]
+fn reap(
+    child: &mut std::process::Child,
+    block: bool,
+) -> std::io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    use std::os::unix::process::ExitStatusExt;
+    let flags = if block { 0 } else { libc::WNOHANG };
+    let mut status = 0;
+    // SAFETY: rusage is plain data, for which all zeroes is a valid value
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: both pointers are to live locals that wait4 only writes
+        // through; the pid is our unreaped child, so it cannot name another
+        // process
+        let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, flags, &mut ru) };
+        match pid {
+            0 => return Ok(None),
+            p if p > 0 => return Ok(Some((ExitStatus::from_raw(status), Some(usage(&ru))))),
+            _ => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn reap(
+    child: &mut std::process::Child,
+    block: bool,
+) -> std::io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    if block {
+        child.wait().map(|s| Some((s, None)))
+    } else {
+        child.try_wait().map(|s| s.map(|s| (s, None)))
+    }
+}
+
+#[cfg(unix)]
+fn usage(ru: &libc::rusage) -> ResourceUsage {
+    let ms = |tv: libc::timeval| tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000;
+    // Linux reports KiB, macOS bytes
+    let max_rss_kb = if cfg!(target_os = "macos") {
+        ru.ru_maxrss as u64 / 1024
+    } else {
+        ru.ru_maxrss as u64
+    };
+    ResourceUsage {
+        user_ms: ms(ru.ru_utime),
+        sys_ms: ms(ru.ru_stime),
+        max_rss_kb,
+        major_faults: ru.ru_majflt as u64,
+        block_in: ru.ru_inblock as u64,
+        block_out: ru.ru_oublock as u64,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Echo {
+    Stdout,
+    Stderr,
+    /// Cargo JSON messages: show the rendered diagnostic on stderr
+    CargoJson,
+}
+
+/// How long to keep reading a stage's pipes after it exited
+const DRAIN_GRACE: Duration = Duration::from_secs(2);
+
+/// Output read so far from one of a child's pipes, by a thread of its own.
+struct Drain {
+    buf: Arc<Mutex<Vec<u8>>>,
+    reader: std::thread::JoinHandle<()>,
+}
+
+impl Drain {
+    /// Everything read once the pipe closes, or by `deadline`; also whether
+    /// the pipe was still open then (the reader thread is left behind).
+    fn collect(self, deadline: Instant) -> (Vec<u8>, bool) {
+        while !self.reader.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let open = !self.reader.is_finished();
+        let buf = std::mem::take(&mut *self.buf.lock().unwrap());
+        (buf, open)
+    }
+}
+
+/// Collect everything from `r`; with `echo`, also forward each line as it arrives.
+fn drain<R: Read + Send + 'static>(mut r: R, echo: Option<(Stream, Echo)>) -> Drain {
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&buf);
+    let reader = std::thread::spawn(move || {
+        let Some((stream, target)) = echo else {
+            let mut chunk = [0u8; 8192];
+            loop {
+                match r.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => sink.lock().unwrap().extend_from_slice(&chunk[..n]),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+            return;
+        };
+
+        let mut reader = BufReader::new(r);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            sink.lock().unwrap().extend_from_slice(&line);
+            if let Echo::CargoJson = target {
+                if let Some(text) = diagnostics::rendered(&String::from_utf8_lossy(&line)) {
+                    let prefix = &stream.prefix;
+                    let out: String = text.lines().map(|l| format!("{prefix}{l}\n")).collect();
+                    stream.write(true, out.as_bytes());
+                }
+                continue;
+            }
+            if !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+            let mut out = stream.prefix.as_bytes().to_vec();
+            out.extend_from_slice(&line);
+            stream.write(matches!(target, Echo::Stderr), &out);
+        }
+    });
+    Drain { buf, reader }
+}
+
+fn argv(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|p| p.to_string()).collect()
+}
+
+fn cargo_fmt_cmd() -> Vec<String> {
+    argv(&["cargo", "fmt", "--all", "--check"])
+}
+
+fn cargo_fmt_fix_cmd() -> Vec<String> {
+    argv(&["cargo", "fmt", "--all"])
+}
+
+fn cargo_check_cmd() -> Vec<String> {
+    argv(&["cargo", "check", "-q"])
+}
+
+fn cargo_clippy_cmd(cfg: &Config, mode: &str) -> Vec<String> {
+    let mut c = argv(&["cargo", "clippy", "--all-targets"]);
+    if mode == "full" && cfg.pipeline.all_features_in_full {
+        c.push("--all-features".to_string());
     }
     if cfg.pipeline.clippy_deny_warnings {
-        c.arg("--").arg("-D").arg("warnings");
+        c.extend(argv(&["--", "-D", "warnings"]));
     }
     c
 }
 
-fn cargo_test_cmd(cfg: &Config, mode: Mode) -> Command {
-    let mut c = Command::new("cargo");
-    c.arg("test").arg("-q");
-    if mode == Mode::Full && cfg.pipeline.all_features_in_full {
-        c.arg("--all-features");
+/// Applies clippy's machine-applicable suggestions, even to uncommitted files
+fn cargo_clippy_fix_cmd(cfg: &Config, mode: &str) -> Vec<String> {
+    let mut c = argv(&[
+        "cargo",
+        "clippy",
+        "--fix",
+        "--allow-dirty",
+        "--allow-staged",
+        "--all-targets",
+    ]);
+    if mode == "full" && cfg.pipeline.all_features_in_full {
+        c.push("--all-features".to_string());
+    }
+    c
+}
+
+fn cargo_nextest_cmd(cfg: &Config, mode: &str) -> Vec<String> {
+    let tool_config = format!("dwf:{}", nextest::tool_config_path(cfg).display());
+    let mut c = argv(&[
+        "cargo",
+        "nextest",
+        "run",
+        "--profile",
+        nextest::PROFILE,
+        "--tool-config-file",
+        &tool_config,
+    ]);
+    if mode == "full" && cfg.pipeline.all_features_in_full {
+        c.push("--all-features".to_string());
+    }
+    c
+}
+
+fn cargo_test_cmd(cfg: &Config, mode: &str) -> Vec<String> {
+    let mut c = argv(&["cargo", "test", "-q"]);
+    if mode == "full" && cfg.pipeline.all_features_in_full {
+        c.push("--all-features".to_string());
     }
     c
 }
@@ -168,20 +1566,440 @@ pub(crate) fn trim_lines(s: &str, max_lines: usize) -> String {
     out
 }
 
-pub fn print_run_summary(run: &RunRecord) {
-    println!("Mode: {:?} | ok: {}", run.mode, run.ok);
+/// Variables that change what cargo and rustc do, shown in plans when set
+const PLAN_ENV: &[&str] = &[
+    "RUSTFLAGS",
+    "RUSTDOCFLAGS",
+    "RUSTC_WRAPPER",
+    "RUSTUP_TOOLCHAIN",
+    "CARGO_TARGET_DIR",
+    "CARGO_BUILD_JOBS",
+    "CARGO_INCREMENTAL",
+    "RUST_BACKTRACE",
+    "RUST_TEST_THREADS",
+];
+
+/// `dwf run --dry-run`: the stages that would run, in order, with their
+/// command lines, and why the others would not. Nothing runs or is recorded.
+pub fn print_plan(
+    cfg: &Config,
+    mode: &str,
+    opts: &RunOptions,
+    history: &[RunRecord],
+) -> Result<()> {
+    let Scoped {
+        stages,
+        packages,
+        dropped,
+    } = scoped_stages(cfg, mode, opts)?;
+    let adaptive = cfg.pipeline.order == "adaptive";
+    let stages = if adaptive {
+        adaptive_order(stages, history).0
+    } else {
+        stages
+    };
+    let lookup = CacheLookup::new(cfg, opts, &stages)?;
+    let skips = when_skips(cfg, mode, &stages, opts);
+    let jobs = effective_jobs(cfg, opts);
+
+    println!(
+        "Plan for mode `{}` (dry run: nothing is executed or recorded)",
+        mode
+    );
+    println!(
+        "  jobs: {} | fail_fast: {} | cache: {} | order: {}",
+        jobs,
+        cfg.pipeline.fail_fast && !opts.keep_going,
+        if lookup.enabled { "on" } else { "off" },
+        cfg.pipeline.order
+    );
+    if let Some(packages) = &packages {
+        if packages.is_empty() {
+            println!("  packages: (no changed crates)");
+        } else {
+            println!("  packages: {}", packages.join(", "));
+        }
+    }
+    let toolchain = cache::toolchain_version();
+    println!("  rustc: {}", toolchain.lines().next().unwrap_or("unknown"));
+    let env: Vec<String> = PLAN_ENV
+        .iter()
+        .filter_map(|k| std::env::var(k).ok().map(|v| format!("{}={}", k, v)))
+        .collect();
+    if env.is_empty() {
+        println!("  env: (no cargo/rustc variables set)");
+    } else {
+        println!("  env: {}", env.join(" "));
+    }
+
+    let mut estimates = Vec::new();
+    let mut unknown = 0;
+    for (i, st) in stages.iter().enumerate() {
+        let cached = lookup.hits[i].is_some();
+        let skip = skips[i].as_deref();
+        let estimate = if cached || skip.is_some() {
+            Some(0)
+        } else {
+            expected_ms(history, &st.name)
+        };
+        if estimate.is_none() {
+            unknown += 1;
+        }
+        estimates.push(estimate.unwrap_or(0));
+
+        let mut notes = Vec::new();
+        if !st.needs.is_empty() {
+            notes.push(format!("needs {}", st.needs.join(", ")));
+        }
+        if !st.critical {
+            notes.push("non-critical".to_string());
+        }
+        if let Some(t) = st.timeout {
+            notes.push(format!("timeout {}s", t.as_secs()));
+        }
+        if st.retries > 0 {
+            notes.push(format!("retries {}", st.retries));
+        }
+        if adaptive {
+            let r = stage_rank(history, &st.name);
+            if r.runs > 0 {
+                notes.push(format!("failed {}/{}", r.failures, r.runs));
+            }
+        }
+        match (skip, cached, estimate) {
+            (Some(why), _, _) => notes.push(format!("skipped ({}), will not run", why)),
+            (None, true, _) => notes.push("cached (inputs unchanged), will not run".to_string()),
+            (None, false, Some(ms)) => notes.push(format!("~{} ms", ms)),
+            (None, false, None) => notes.push("no history".to_string()),
+        }
+        println!("  {}. {:<8} {}", i + 1, st.name, notes.join(" | "));
+
+        if st.parts.is_empty() {
+            println!("       $ {}", shell_line(&st.argv));
+        }
+        for part in &st.parts {
+            println!("       $ {}    [{}]", shell_line(&part.argv), part.label);
+        }
+        if opts.fix {
+            if let Some(fix) = &st.fix {
+                println!("       fix first: $ {}", shell_line(fix));
+            }
+        }
+    }
+
+    let mut skipped = mode_skips(cfg, mode);
+    skipped.extend(
+        dropped
+            .into_iter()
+            .map(|n| (n, "no changed crates to check".to_string())),
+    );
+    if !skipped.is_empty() {
+        println!("Skipped:");
+        for (name, why) in &skipped {
+            println!("  - {:<8} {}", name, why);
+        }
+    }
+
+    let total = if jobs == 1 {
+        estimates.iter().sum()
+    } else {
+        critical_path_ms(&stages, &estimates)
+    };
+    let caveat = if unknown > 0 {
+        format!(" ({} stage(s) without history not counted)", unknown)
+    } else {
+        String::new()
+    };
+    println!("Expected: ~{} ms{}", total, caveat);
+    Ok(())
+}
+
+/// Stages the full set has but `mode` leaves out, with the reason.
+fn mode_skips(cfg: &Config, mode: &str) -> Vec<(String, String)> {
+    let candidates: Vec<(String, Option<String>)> = if cfg.pipeline.stages.is_empty() {
+        config::BUILTIN_STAGES
+            .iter()
+            .map(|n| (n.to_string(), (*n == "test").then(|| "full".to_string())))
+            .collect()
+    } else {
+        cfg.pipeline
+            .stages
+            .iter()
+            .map(|s| (s.name.clone(), s.mode.clone()))
+            .collect()
+    };
+    let reason = if cfg.modes.contains_key(mode) {
+        format!("not listed in [modes.{}]", mode)
+    } else {
+        "full mode only".to_string()
+    };
+    candidates
+        .into_iter()
+        .filter(|(name, stage_mode)| !in_mode(cfg, mode, name, stage_mode.as_deref()))
+        .map(|(name, _)| (name, reason.clone()))
+        .collect()
+}
+
+/// Recent runs consulted for adaptive ordering and plan estimates
+pub const HISTORY_WINDOW: usize = 50;
+
+/// Stages in dependency order where, among those whose `needs` are placed,
+/// the one most likely to fail per expected millisecond goes first.
+/// Failure rates are smoothed, (failures + 1) / (runs + 2), so a single bad
+/// run does not dominate; stages without timings go first, as declared.
+fn adaptive_order(stages: Vec<Stage>, history: &[RunRecord]) -> (Vec<Stage>, Vec<StageRank>) {
+    let ranks: Vec<StageRank> = stages
+        .iter()
+        .map(|st| stage_rank(history, &st.name))
+        .collect();
+    let score = |r: &StageRank| match r.expected_ms {
+        Some(ms) => (r.failures as f64 + 1.0) / (r.runs as f64 + 2.0) / ms.max(1) as f64,
+        None => f64::INFINITY,
+    };
+
+    let mut placed = vec![false; stages.len()];
+    let mut order: Vec<usize> = Vec::new();
+    while order.len() < stages.len() {
+        let ready = (0..stages.len()).filter(|&i| !placed[i]).filter(|&i| {
+            stages[i]
+                .needs
+                .iter()
+                .all(|n| match stages.iter().position(|s| &s.name == n) {
+                    Some(j) => placed[j],
+                    None => true,
+                })
+        });
+        // Ties keep declaration order
+        let next = ready.max_by(|&a, &b| {
+            score(&ranks[a])
+                .total_cmp(&score(&ranks[b]))
+                .then(b.cmp(&a))
+        });
+        let Some(i) = next else { break };
+        placed[i] = true;
+        order.push(i);
+    }
+    // Only reachable with a dependency cycle, which config validation rejects
+    order.extend((0..stages.len()).filter(|&i| !placed[i]));
+
+    let mut ranked: Vec<(usize, Stage, StageRank)> = stages
+        .into_iter()
+        .zip(ranks)
+        .enumerate()
+        .map(|(i, (st, r))| (order.iter().position(|&o| o == i).unwrap_or(i), st, r))
+        .collect();
+    ranked.sort_by_key(|(pos, _, _)| *pos);
+    ranked.into_iter().map(|(_, st, r)| (st, r)).unzip()
+}
+
+fn stage_rank(history: &[RunRecord], stage: &str) -> StageRank {
+    let ran: Vec<StepStatus> = history
+        .iter()
+        .filter(|r| !r.aborted)
+        .flat_map(|r| &r.steps)
+        .filter(|s| s.name == stage)
+        .map(|s| s.status())
+        .filter(|st| {
+            matches!(
+                st,
+                StepStatus::Passed | StepStatus::Failed | StepStatus::TimedOut | StepStatus::Flaky
+            )
+        })
+        .collect();
+    StageRank {
+        name: stage.to_string(),
+        runs: ran.len() as u32,
+        failures: ran.iter().filter(|st| st.is_failure()).count() as u32,
+        expected_ms: expected_ms(history, stage),
+    }
+}
+
+/// "failed 2/10, ~850 ms" for the summary and plan
+fn rank_note(r: &StageRank) -> String {
+    match r.expected_ms {
+        Some(ms) => format!("failed {}/{}, ~{} ms", r.failures, r.runs, ms),
+        None => "no history".to_string(),
+    }
+}
+
+/// Median duration of recent runs of `stage` that actually executed it.
+fn expected_ms(history: &[RunRecord], stage: &str) -> Option<u64> {
+    let mut durations: Vec<u64> = history
+        .iter()
+        .flat_map(|r| &r.steps)
+        .filter(|s| s.name == stage)
+        .filter(|s| matches!(s.status(), StepStatus::Passed | StepStatus::Failed))
+        .map(|s| s.duration_ms)
+        .collect();
+    if durations.is_empty() {
+        return None;
+    }
+    durations.sort_unstable();
+    Some(durations[durations.len() / 2])
+}
+
+/// Longest chain of `needs` when stages may run side by side.
+fn critical_path_ms(stages: &[Stage], estimates: &[u64]) -> u64 {
+    let mut finish = vec![0u64; stages.len()];
+    // Needs can point forward in declaration order; the graph is acyclic, so
+    // one pass per stage settles every chain
+    for _ in 0..stages.len() {
+        for (i, st) in stages.iter().enumerate() {
+            let ready = st
+                .needs
+                .iter()
+                .filter_map(|n| stages.iter().position(|s| &s.name == n))
+                .map(|j| finish[j])
+                .max()
+                .unwrap_or(0);
+            finish[i] = ready + estimates[i];
+        }
+    }
+    finish.into_iter().max().unwrap_or(0)
+}
+
+/// `argv` as one copy-pasteable shell line.
+fn shell_line(argv: &[String]) -> String {
+    argv.iter()
+        .map(|a| {
+            let plain = !a.is_empty()
+                && a.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_=./:,+@%".contains(c));
+            if plain {
+                a.clone()
+            } else {
+                format!("'{}'", a.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Print the outcome of `run`. After `streamed` output the terminal already
+/// shows each step's stderr, so excerpts are left out.
+pub fn print_run_summary(run: &RunRecord, streamed: bool) {
+    println!("Mode: {} | ok: {}", run.mode, run.ok);
+    if run.aborted {
+        println!("Aborted: interrupted before the pipeline finished");
+    }
+    if let Some(tree) = &run.tree {
+        println!(
+            "Checked: staged changes (tree {})",
+            &tree[..tree.len().min(12)]
+        );
+    }
+    if !run.order.is_empty() {
+        let order: Vec<String> = run
+            .order
+            .iter()
+            .map(|r| format!("{} ({})", r.name, rank_note(r)))
+            .collect();
+        println!(
+            "Order (adaptive; likely failures per ms first, needs respected): {}",
+            order.join(" → ")
+        );
+    }
+    if let Some(packages) = &run.packages {
+        if packages.is_empty() {
+            println!("Packages: (no changed crates)");
+        } else {
+            println!("Packages: {}", packages.join(", "));
+        }
+    }
     for s in &run.steps {
-        let status = if s.ok { "✅" } else { "❌" };
+        let status = match s.status() {
+            StepStatus::Passed => "✅",
+            StepStatus::Cached => "♻️",
+            StepStatus::Flaky => "🔁",
+            StepStatus::Skipped | StepStatus::Blocked => "⏭️",
+            StepStatus::Cancelled => "⏹️",
+            StepStatus::TimedOut => "⏱️",
+            StepStatus::Failed if s.critical => "❌",
+            StepStatus::Failed => "⚠️",
+        };
+        if s.status() == StepStatus::Cached {
+            println!(
+                "  {} {:<6}  {} ms  (cached, inputs unchanged)",
+                status, s.name, s.duration_ms
+            );
+            continue;
+        }
+        if matches!(s.status(), StepStatus::Skipped | StepStatus::Blocked) {
+            println!(
+                "  {} {:<6}  skipped ({})",
+                status,
+                s.name,
+                s.skip_reason.as_deref().unwrap_or("not run")
+            );
+            continue;
+        }
+        let usage = s
+            .resources
+            .map(|u| format!("  cpu {} ms  peak {} MiB", u.cpu_ms(), u.max_rss_kb / 1024))
+            .unwrap_or_default();
         println!(
-            "  {} {:<6}  {} ms  exit={:?}",
-            status, s.name, s.duration_ms, s.exit_code
+            "  {} {:<6}  {} ms  exit={:?}{}",
+            status, s.name, s.duration_ms, s.exit_code, usage
         );
-        if !s.ok && !s.stderr_excerpt.trim().is_empty() {
+        if s.attempts.len() > 1 {
+            let tries: Vec<String> = s
+                .attempts
+                .iter()
+                .map(|a| format!("{:?} {} ms", a.status, a.duration_ms).to_lowercase())
+                .collect();
+            println!("      attempts: {}", tries.join(", "));
+        }
+        for sub in &s.substeps {
+            let mark = if sub.ok { "✓" } else { "✗" };
+            println!(
+                "      {} {:<16} {} ms  exit={:?}",
+                mark, sub.name, sub.duration_ms, sub.exit_code
+            );
+        }
+        if let Some(t) = &s.tests {
+            println!(
+                "      tests: {} passed, {} failed, {} ignored",
+                t.passed, t.failed, t.ignored
+            );
+            if t.slowest.is_empty() && t.passed + t.failed > 0 {
+                // Stable libtest never prints per-test times
+                println!(
+                    "      (no per-test times: use test_runner = \"nextest\", or nightly with --report-time)"
+                );
+            } else if !t.slowest.is_empty() {
+                let slowest: Vec<String> = t
+                    .slowest
+                    .iter()
+                    .take(3)
+                    .map(|t| format!("{} ({} ms)", t.name, t.duration_ms))
+                    .collect();
+                println!("      slowest: {}", slowest.join(", "));
+            }
+        }
+        let failed_tests = s.tests.as_ref().map_or(&[][..], |t| &t.failures[..]);
+        if !s.diagnostics.is_empty() {
+            print_diagnostics(&s.diagnostics);
+        } else if !failed_tests.is_empty() {
+            print_failed_tests(failed_tests);
+        } else if !streamed && s.status().is_failure() && !s.stderr_excerpt.trim().is_empty() {
             println!("--- stderr (excerpt) ---");
             println!("{}", s.stderr_excerpt);
             println!("------------------------");
         }
     }
+    if run.failure_stages.len() > 1 {
+        println!("Failed stages: {}", run.failure_stages.join(", "));
+    }
+    if run.autofix {
+        if run.fixed_files.is_empty() {
+            println!("Auto-fix: no files changed");
+        } else {
+            println!("Auto-fix changed {} file(s):", run.fixed_files.len());
+            for f in &run.fixed_files {
+                println!("  {}", f);
+            }
+        }
+    }
     println!("TTS: {} ms", run.tts_ms);
     match run.ttg_ms {
         Some(ms) => println!("TTG: {} ms", ms),
@@ -190,9 +2008,50 @@ pub fn print_run_summary(run: &RunRecord) {
     println!("Total: {} ms", run.total_ms);
 }
 
+fn print_failed_tests(failures: &[FailedTest]) {
+    for f in failures {
+        match f.duration_ms {
+            Some(ms) => println!("      ✗ {} ({} ms)", f.name, ms),
+            None => println!("      ✗ {}", f.name),
+        }
+        for line in f.message.lines() {
+            println!("          {}", line);
+        }
+    }
+}
+
+/// Most diagnostics listed per step before the rest are summarized
+const MAX_LISTED_DIAGNOSTICS: usize = 20;
+
+fn print_diagnostics(diags: &[Diagnostic]) {
+    let errors = diags.iter().filter(|d| d.level == "error").count();
+    let warnings = diags.iter().filter(|d| d.level == "warning").count();
+    println!("      {} error(s), {} warning(s)", errors, warnings);
+
+    // Errors first, then warnings; notes and help are attached context
+    let mut listed: Vec<&Diagnostic> = diags.iter().filter(|d| d.level == "error").collect();
+    listed.extend(diags.iter().filter(|d| d.level == "warning"));
+    for d in listed.iter().take(MAX_LISTED_DIAGNOSTICS) {
+        let tag = match &d.code {
+            Some(code) => format!("{}[{}]", d.level, code),
+            None => d.level.clone(),
+        };
+        let loc = match (&d.file, d.line) {
+            (Some(f), Some(l)) => format!("{}:{}", f, l),
+            (Some(f), None) => f.clone(),
+            _ => "-".to_string(),
+        };
+        println!("      {} {}  {}", tag, loc, d.message);
+    }
+    if listed.len() > MAX_LISTED_DIAGNOSTICS {
+        println!("      … and {} more", listed.len() - MAX_LISTED_DIAGNOSTICS);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::trim_lines;
+    use super::*;
+    use crate::config::StageConfig;
 
     #[test]
     fn trims_to_max_lines() {
@@ -217,4 +2076,670 @@ mod tests {
         let out = trim_lines(s, 0);
         assert!(out.is_empty());
     }
+
+    fn stage(name: &str, mode: Option<&str>) -> StageConfig {
+        StageConfig {
+            name: name.to_string(),
+            cmd: vec!["true".to_string()],
+            critical: true,
+            mode: mode.map(|m| m.to_string()),
+            needs: None,
+            timeout_secs: None,
+            inputs: Vec::new(),
+            retries: 0,
+            retry_delay_ms: 0,
+            fix: None,
+            when: None,
+        }
+    }
+
+    #[test]
+    fn default_stages_when_none_declared() {
+        let cfg = Config::default();
+        let plan = plan_stages(&cfg, "fast").unwrap();
+        let names: Vec<_> = plan.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["fmt", "check", "clippy"]);
+        assert!(!plan[0].json);
+        assert!(plan[1].argv.contains(&"--message-format=json".to_string()));
+        assert_eq!(plan_stages(&cfg, "full").unwrap().len(), 4);
+
+        let mut cfg = Config::default();
+        cfg.pipeline.retries = 2;
+        let test = plan_stages(&cfg, "full").unwrap().pop().unwrap();
+        assert_eq!((test.name.as_str(), test.retries), ("test", 2));
+    }
+
+    #[test]
+    fn builtin_test_stage_is_only_cached_with_test_inputs() {
+        let storage = tempfile::tempdir().unwrap();
+        let mut cfg = Config::default();
+        cfg.storage.dir = storage.path().to_string_lossy().to_string();
+        // Stages a green run of the unchanged tree stands in for
+        let cached = |cfg: &Config| -> Vec<String> {
+            let stages = default_stages(cfg, "full");
+            let opts = RunOptions::default();
+            let passed = CmdResult {
+                status: StepStatus::Passed,
+                ..shortcut_result(Instant::now(), &Shortcut::Cached { duration_ms: 5 })
+            };
+            let steps: Vec<_> = stages.iter().map(|st| step_record(st, &passed)).collect();
+            let mut lookup = CacheLookup::new(cfg, &opts, &stages).unwrap();
+            lookup.record(cfg, &stages, &steps).unwrap();
+            let lookup = CacheLookup::new(cfg, &opts, &stages).unwrap();
+            stages
+                .iter()
+                .zip(&lookup.hits)
+                .filter(|(_, hit)| hit.is_some())
+                .map(|(st, _)| st.name.clone())
+                .collect()
+        };
+
+        // Tests may read fixtures no .rs glob covers, so they always run again
+        assert_eq!(cached(&cfg), ["fmt", "check", "clippy"]);
+        cfg.pipeline.test_inputs = vec!["**/*.rs".into(), "tests/fixtures/**".into()];
+        assert_eq!(cached(&cfg), ["fmt", "check", "clippy", "test"]);
+    }
+
+    #[test]
+    fn declared_stages_filtered_by_mode() {
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![stage("doc", None), stage("deny", Some("full"))];
+        cfg.pipeline.stages[0].cmd = vec!["cargo".into(), "clippy".into()];
+        assert_eq!(
+            plan_stages(&cfg, "fast").unwrap()[0].argv,
+            ["cargo", "clippy"]
+        );
+
+        let fast: Vec<_> = plan_stages(&cfg, "fast")
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(fast, ["doc"]);
+
+        let full: Vec<_> = plan_stages(&cfg, "full")
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(full, ["doc", "deny"]);
+    }
+
+    #[test]
+    fn stages_without_needs_wait_for_previous() {
+        let mut cfg = Config::default();
+        let mut lint = stage("lint", None);
+        lint.needs = Some(Vec::new());
+        cfg.pipeline.stages = vec![stage("doc", None), stage("deny", None), lint];
+
+        let plan = plan_stages(&cfg, "fast").unwrap();
+        assert!(plan[0].needs.is_empty());
+        assert_eq!(plan[1].needs, ["doc"]);
+        assert!(plan[2].needs.is_empty());
+    }
+
+    #[test]
+    fn critical_failure_cancels_running_sibling() {
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![
+            StageConfig {
+                cmd: vec!["sleep".into(), "5".into()],
+                needs: Some(Vec::new()),
+                ..stage("slow", None)
+            },
+            StageConfig {
+                cmd: vec!["false".into()],
+                needs: Some(Vec::new()),
+                ..stage("bad", None)
+            },
+            stage("after", None),
+        ];
+        let opts = RunOptions {
+            jobs: Some(2),
+            ..Default::default()
+        };
+
+        let run = run_pipeline(&cfg, "fast", &opts).unwrap();
+        assert!(!run.ok);
+        assert_eq!(run.failure_stage.as_deref(), Some("bad"));
+        assert_eq!(run.steps.len(), 3);
+        assert_eq!(run.steps[0].status(), StepStatus::Cancelled);
+        assert_eq!(run.steps[2].status(), StepStatus::Blocked);
+        assert_eq!(
+            run.steps[2].skip_reason.as_deref(),
+            Some("dependency failed: bad")
+        );
+        assert!(run.total_ms < 5000);
+    }
+
+    #[test]
+    fn keep_going_records_every_failure() {
+        let mut cfg = Config::default();
+        let failing = |name: &str| StageConfig {
+            cmd: vec!["false".into()],
+            ..stage(name, None)
+        };
+        cfg.pipeline.stages = vec![
+            StageConfig {
+                needs: Some(Vec::new()),
+                ..failing("fmt")
+            },
+            StageConfig {
+                needs: Some(Vec::new()),
+                ..stage("check", None)
+            },
+            StageConfig {
+                needs: Some(vec!["check".into()]),
+                ..failing("clippy")
+            },
+        ];
+        let opts = RunOptions {
+            jobs: Some(1),
+            keep_going: true,
+            ..Default::default()
+        };
+
+        let run = run_pipeline(&cfg, "fast", &opts).unwrap();
+        assert!(!run.ok);
+        assert_eq!(run.steps.len(), 3);
+        assert_eq!(run.failure_stage.as_deref(), Some("fmt"));
+        assert_eq!(run.failure_stages, ["fmt", "clippy"]);
+    }
+
+    #[test]
+    fn keep_going_runs_the_default_chain_past_failures() {
+        let mut cfg = Config::default();
+        cfg.pipeline.fail_fast = false;
+        let run = |failing: &[&str]| -> Vec<(String, StepStatus)> {
+            let mut stages = default_stages(&cfg, "full");
+            for st in &mut stages {
+                let fails = failing.contains(&st.name.as_str());
+                st.argv = vec![if fails { "false" } else { "true" }.to_string()];
+                st.json = false;
+                st.junit = None;
+            }
+            let shortcuts: Vec<Option<Shortcut>> = stages.iter().map(|_| None).collect();
+            let steps = execute(
+                &cfg,
+                &stages,
+                &shortcuts,
+                &RunOptions::default(),
+                Instant::now(),
+            )
+            .unwrap();
+            steps.iter().map(|s| (s.name.clone(), s.status())).collect()
+        };
+        let names = |statuses: &[(&str, StepStatus)]| -> Vec<(String, StepStatus)> {
+            statuses.iter().map(|(n, s)| (n.to_string(), *s)).collect()
+        };
+
+        assert_eq!(
+            run(&["fmt", "clippy"]),
+            names(&[
+                ("fmt", StepStatus::Failed),
+                ("check", StepStatus::Passed),
+                ("clippy", StepStatus::Failed),
+                ("test", StepStatus::Passed),
+            ])
+        );
+        // Nothing past a compile error can run; the independent fmt still does
+        assert_eq!(
+            run(&["fmt", "check"]),
+            names(&[
+                ("fmt", StepStatus::Failed),
+                ("check", StepStatus::Failed),
+                ("clippy", StepStatus::Blocked),
+                ("test", StepStatus::Blocked),
+            ])
+        );
+    }
+
+    #[test]
+    fn timeout_kills_process_group_and_keeps_stderr() {
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![StageConfig {
+            // The grandchild `sleep` holds the pipes open unless the group dies
+            cmd: vec![
+                "sh".into(),
+                "-c".into(),
+                "echo started >&2; sleep 30 & wait".into(),
+            ],
+            timeout_secs: Some(1),
+            ..stage("hang", None)
+        }];
+
+        let run = run_pipeline(&cfg, "fast", &RunOptions::default()).unwrap();
+        let step = &run.steps[0];
+        assert_eq!(step.status(), StepStatus::TimedOut);
+        assert!(step.stderr_excerpt.contains("started"));
+        assert_eq!(run.failure_stage.as_deref(), Some("hang"));
+        assert!(run.total_ms < 10_000);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn escaped_grandchild_does_not_hold_up_the_run() {
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![StageConfig {
+            // setsid leaves the process group, so the kill misses it
+            cmd: vec![
+                "sh".into(),
+                "-c".into(),
+                "echo started >&2; setsid sleep 30 & sleep 30".into(),
+            ],
+            timeout_secs: Some(1),
+            ..stage("hang", None)
+        }];
+
+        let run = run_pipeline(&cfg, "fast", &RunOptions::default()).unwrap();
+        let step = &run.steps[0];
+        assert_eq!(step.status(), StepStatus::TimedOut);
+        assert!(step.stderr_excerpt.contains("started"));
+        assert!(step.stderr_excerpt.contains("still holds it open"));
+        assert!(run.total_ms < 10_000);
+    }
+
+    #[test]
+    fn streaming_still_captures_stderr() {
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![StageConfig {
+            cmd: vec![
+                "sh".into(),
+                "-c".into(),
+                "echo out; echo err >&2; exit 1".into(),
+            ],
+            ..stage("noisy", None)
+        }];
+        let shown = Arc::new(Mutex::new(Vec::new()));
+        let opts = RunOptions {
+            stream: true,
+            capture: Some(shown.clone()),
+            ..Default::default()
+        };
+
+        let run = run_pipeline(&cfg, "fast", &opts).unwrap();
+        assert_eq!(run.steps[0].stderr_excerpt, "err\n");
+        let shown = String::from_utf8(shown.lock().unwrap().clone()).unwrap();
+        assert!(shown.contains("out\n") && shown.contains("err\n"));
+    }
+
+    #[test]
+    fn fixers_run_before_checks() {
+        let repo = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            let ok = Command::new("git")
+                .arg("-C")
+                .arg(repo.path())
+                .args(args)
+                .status()
+                .unwrap()
+                .success();
+            assert!(ok, "git {:?}", args);
+        };
+        git(&["init", "-q"]);
+        std::fs::write(repo.path().join("lint.txt"), "bad\n").unwrap();
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![StageConfig {
+            cmd: vec!["grep".into(), "-q".into(), "good".into(), "lint.txt".into()],
+            fix: Some(vec![
+                "sh".into(),
+                "-c".into(),
+                "echo good > lint.txt".into(),
+            ]),
+            ..stage("lint", None)
+        }];
+        let opts = RunOptions {
+            fix: true,
+            workdir: Some(repo.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        let run = run_pipeline(&cfg, "fast", &opts).unwrap();
+        assert!(run.ok);
+        assert!(run.autofix);
+        assert_eq!(run.fixed_files, ["lint.txt"]);
+    }
+
+    #[test]
+    fn records_libtest_results() {
+        let mut cfg = Config::default();
+        let libtest_output = |name: &str| {
+            StageConfig {
+            cmd: vec![
+                "sh".into(),
+                "-c".into(),
+                "printf 'failures:\\n    it::works\\n\\ntest result: FAILED. 4 passed; 1 failed; 2 ignored;\\n'; exit 101".into(),
+            ],
+            needs: Some(Vec::new()),
+            ..stage(name, None)
+        }
+        };
+        cfg.pipeline.stages = vec![libtest_output("test"), libtest_output("lint")];
+        let opts = RunOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+
+        let run = run_pipeline(&cfg, "fast", &opts).unwrap();
+        let t = run.steps[0].tests.as_ref().unwrap();
+        assert_eq!((t.passed, t.failed, t.ignored), (4, 1, 2));
+        assert_eq!(t.failures[0].name, "it::works");
+        // Not a test runner, however much its output looks like one
+        assert!(run.steps[1].tests.is_none());
+    }
+
+    #[test]
+    fn abort_kills_stage_and_marks_run() {
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![
+            StageConfig {
+                cmd: vec!["sleep".into(), "5".into()],
+                ..stage("slow", None)
+            },
+            stage("after", None),
+        ];
+        let abort = Arc::new(AtomicBool::new(false));
+        let opts = RunOptions {
+            abort: Some(abort.clone()),
+            ..Default::default()
+        };
+
+        let flag = abort.clone();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            flag.store(true, Ordering::SeqCst);
+        });
+        let run = run_pipeline(&cfg, "fast", &opts).unwrap();
+        interrupter.join().unwrap();
+        assert!(run.aborted);
+        assert!(!run.ok);
+        assert_eq!(run.ttg_ms, None);
+        assert_eq!(run.steps.len(), 1);
+        assert_eq!(run.steps[0].status(), StepStatus::Cancelled);
+        assert!(run.total_ms < 5000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn records_child_cpu_time() {
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![StageConfig {
+            // Busy loop in a grandchild the shell waits for
+            cmd: vec![
+                "sh".into(),
+                "-c".into(),
+                "sh -c 'i=0; while [ $i -lt 30000 ]; do i=$((i+1)); done'".into(),
+            ],
+            ..stage("busy", None)
+        }];
+
+        let run = run_pipeline(&cfg, "fast", &RunOptions::default()).unwrap();
+        let usage = run.steps[0].resources.unwrap();
+        assert!(usage.cpu_ms() > 0);
+        assert!(usage.max_rss_kb > 0);
+    }
+
+    #[test]
+    fn plan_explains_skips_and_chains() {
+        let mut cfg = Config::default();
+        cfg.modes.insert(
+            "pre".to_string(),
+            config::ModeConfig {
+                stages: vec!["fmt".into()],
+                args: Default::default(),
+            },
+        );
+        let skips: Vec<_> = mode_skips(&cfg, "pre")
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(skips, ["check", "clippy", "test"]);
+        assert_eq!(mode_skips(&cfg, "fast")[0].1, "full mode only");
+
+        // fmt || check -> clippy -> test
+        let stages = plan_stages(&cfg, "full").unwrap();
+        assert_eq!(critical_path_ms(&stages, &[500, 100, 200, 300]), 600);
+
+        assert_eq!(
+            shell_line(&["sh".into(), "-c".into(), "echo it's".into()]),
+            "sh -c 'echo it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn pass_on_retry_is_flaky() {
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = tmp.path().join("tried");
+        let mut cfg = Config::default();
+        cfg.pipeline.stages = vec![
+            StageConfig {
+                // Fails the first time, passes once the marker exists
+                cmd: vec![
+                    "sh".into(),
+                    "-c".into(),
+                    format!("test -f {0} || {{ touch {0}; exit 1; }}", marker.display()),
+                ],
+                retries: 2,
+                ..stage("it", None)
+            },
+            StageConfig {
+                cmd: vec!["false".into()],
+                retries: 1,
+                ..stage("broken", None)
+            },
+        ];
+        let opts = RunOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+
+        let run = run_pipeline(&cfg, "fast", &opts).unwrap();
+        assert_eq!(run.steps[0].status(), StepStatus::Flaky);
+        assert!(run.steps[0].ok);
+        let tries: Vec<_> = run.steps[0].attempts.iter().map(|a| a.status).collect();
+        assert_eq!(tries, [StepStatus::Failed, StepStatus::Passed]);
+        assert_eq!(run.steps[1].status(), StepStatus::Failed);
+        assert_eq!(run.steps[1].attempts.len(), 2);
+        assert_eq!(run.failure_stages, ["broken"]);
+    }
+
+    fn mk(name: &str, needs: &[&str]) -> Stage {
+        Stage {
+            name: name.to_string(),
+            argv: vec!["true".into()],
+            critical: true,
+            needs: needs.iter().map(|n| n.to_string()).collect(),
+            compiles: false,
+            timeout: None,
+            json: false,
+            inputs: Vec::new(),
+            parts: Vec::new(),
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            fix: None,
+            junit: None,
+            when_changed: Vec::new(),
+            workdir: None,
+            env: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn adaptive_order_puts_cheap_likely_failures_first() {
+        use StepStatus::{Cached, Failed, Passed};
+        let run = |steps: &[(&str, StepStatus, u64)]| RunRecord::for_test("full", steps);
+        let history = vec![
+            run(&[("fmt", Passed, 2000), ("check", Failed, 900)]),
+            run(&[("fmt", Passed, 2000), ("check", Passed, 1000)]),
+            run(&[
+                ("fmt", Passed, 2000),
+                ("check", Cached, 0),
+                ("clippy", Failed, 50),
+            ]),
+        ];
+        let stages = vec![
+            mk("fmt", &[]),
+            mk("check", &[]),
+            mk("clippy", &["check"]),
+            mk("test", &["clippy"]),
+        ];
+
+        let (stages, ranks) = adaptive_order(stages, &history);
+        let names: Vec<_> = stages.iter().map(|s| s.name.as_str()).collect();
+        // test has no history, so it runs as soon as clippy lets it
+        assert_eq!(names, ["check", "clippy", "test", "fmt"]);
+        assert_eq!((ranks[0].failures, ranks[0].runs), (1, 2));
+        assert_eq!(ranks[0].expected_ms, Some(1000));
+        assert_eq!(ranks[2].expected_ms, None);
+    }
+
+    #[test]
+    fn when_changed_skips_stages_without_relevant_changes() {
+        let cfg = Config::default();
+        let when = |name: &str, needs: &[&str], globs: &[&str]| Stage {
+            when_changed: globs.iter().map(|g| g.to_string()).collect(),
+            ..mk(name, needs)
+        };
+        let stages = vec![
+            when("docs", &[], &["docs/**", "*.md"]),
+            when("check", &[], &["src/**/*.rs"]),
+            mk("publish", &["docs"]),
+        ];
+        let skips = unchanged_stages(&stages, &[PathBuf::from("src/runner/exec.rs")]);
+        assert_eq!(skips, [Some("no relevant changes".to_string()), None, None]);
+        let nested_md = unchanged_stages(&stages, &[PathBuf::from("crates/core/CHANGELOG.md")]);
+        assert_eq!(nested_md[0], None);
+
+        let shortcuts: Vec<_> = skips
+            .into_iter()
+            .map(|s| s.map(|reason| Shortcut::Skipped { reason }))
+            .collect();
+        let steps = execute(
+            &cfg,
+            &stages,
+            &shortcuts,
+            &RunOptions {
+                jobs: Some(1),
+                ..Default::default()
+            },
+            Instant::now(),
+        )
+        .unwrap();
+        assert_eq!(steps[0].status(), StepStatus::Skipped);
+        assert_eq!(steps[0].skip_reason.as_deref(), Some("no relevant changes"));
+        assert!(steps[0].ok);
+        // A skipped stage does not hold back what depends on it
+        assert_eq!(steps[2].status(), StepStatus::Passed);
+    }
+
+    #[test]
+    fn cache_hit_skips_stage_and_unblocks_dependents() {
+        let cfg = Config::default();
+        let stages = vec![mk("check", &[]), mk("clippy", &["check"])];
+
+        let steps = execute(
+            &cfg,
+            &stages,
+            &[Some(Shortcut::Cached { duration_ms: 1234 }), None],
+            &RunOptions::default(),
+            Instant::now(),
+        )
+        .unwrap();
+        assert_eq!(steps[0].status(), StepStatus::Cached);
+        assert_eq!(steps[0].duration_ms, 1234);
+        assert!(steps[0].ok);
+        assert_eq!(steps[1].status(), StepStatus::Passed);
+    }
+
+    #[test]
+    fn feature_matrix_splits_stage_per_package_and_combination() {
+        let mut cfg = Config::default();
+        cfg.pipeline.all_features_in_full = true;
+        let m: config::FeatureMatrixConfig =
+            toml::from_str("no_default = true\neach_feature = true").unwrap();
+        let member = |name: &str, features: &[&str]| workspace::Member {
+            name: name.to_string(),
+            dir: PathBuf::from("/ws").join(name),
+            deps: Vec::new(),
+            features: features.iter().map(|f| f.to_string()).collect(),
+        };
+        let members = [member("core", &["std"]), member("cli", &[])];
+        let clippy = plan_stages(&cfg, "full")
+            .unwrap()
+            .into_iter()
+            .find(|s| s.name == "clippy")
+            .unwrap();
+
+        let st = with_feature_parts(&m, clippy, &members);
+        let labels: Vec<_> = st.parts.iter().map(|p| p.label.as_str()).collect();
+        assert_eq!(
+            labels,
+            [
+                "core/default",
+                "core/no-default",
+                "core/std",
+                "cli/default",
+                "cli/no-default"
+            ]
+        );
+        let std = &st.parts[2].argv;
+        assert_eq!(&std[..4], ["cargo", "clippy", "-p", "core"]);
+        assert!(!std.contains(&"--all-features".to_string()));
+        let dashes = std.iter().position(|a| a == "--").unwrap();
+        assert_eq!(
+            &std[dashes - 3..dashes],
+            ["--no-default-features", "--features", "std"]
+        );
+    }
+
+    #[test]
+    fn package_scope_splits_cargo_stages() {
+        let cfg = Config::default();
+        let stages = plan_stages(&cfg, "full").unwrap();
+        let scoped = scope_to_packages(stages.clone(), &["core".into(), "api".into()]);
+
+        let fmt = scoped.iter().find(|s| s.name == "fmt").unwrap();
+        assert!(fmt.parts.is_empty());
+        let clippy = scoped.iter().find(|s| s.name == "clippy").unwrap();
+        let labels: Vec<_> = clippy.parts.iter().map(|p| p.label.as_str()).collect();
+        assert_eq!(labels, ["core", "api"]);
+        assert_eq!(
+            &clippy.parts[0].argv[..4],
+            ["cargo", "clippy", "-p", "core"]
+        );
+
+        let names: Vec<_> = scope_to_packages(stages, &[])
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, ["fmt"]);
+    }
+
+    #[test]
+    fn custom_mode_selects_stages_and_extra_args() {
+        let mut cfg = Config::default();
+        cfg.modes.insert(
+            "ci".to_string(),
+            config::ModeConfig {
+                stages: vec!["clippy".into(), "test".into()],
+                args: [("clippy".to_string(), vec!["--all-features".to_string()])]
+                    .into_iter()
+                    .collect(),
+            },
+        );
+
+        let plan = plan_stages(&cfg, "ci").unwrap();
+        let names: Vec<_> = plan.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["clippy", "test"]);
+        let dd = plan[0].argv.iter().position(|a| a == "--").unwrap();
+        let feat = plan[0]
+            .argv
+            .iter()
+            .position(|a| a == "--all-features")
+            .unwrap();
+        assert!(feat < dd);
+
+        let err = plan_stages(&cfg, "nightly").unwrap_err().to_string();
+        assert!(err.contains("available: ci, fast, full"));
+    }
 }
@@ -0,0 +1,79 @@
+use crate::workspace;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// The git index exported to a temporary directory; removed on drop.
+pub struct StagedTree {
+    /// Hash of the tree the index describes (`git write-tree`)
+    pub tree: String,
+    /// Where the current directory's counterpart lives in the export
+    pub workdir: PathBuf,
+    root: PathBuf,
+}
+
+/// Export what is staged for commit. Unstaged edits and untracked files
+/// stay behind, so checks see exactly what `git commit` would record.
+pub fn export() -> Result<StagedTree> {
+    export_from(Path::new("."))
+}
+
+/// Export the index of the repository `dir` belongs to.
+fn export_from(dir: &Path) -> Result<StagedTree> {
+    let dir = dir.display().to_string();
+    let git = |args: &[&str]| workspace::git(&[&["-C", dir.as_str()], args].concat());
+    let tree = git(&["write-tree"])
+        .context("write the index as a tree (unresolved conflicts?)")?
+        .trim()
+        .to_string();
+    let top = git(&["rev-parse", "--show-toplevel"])?;
+    let prefix = git(&["rev-parse", "--show-prefix"])?;
+
+    let root = std::env::temp_dir().join(format!("dwf-staged-{}", std::process::id()));
+    if root.exists() {
+        std::fs::remove_dir_all(&root).with_context(|| format!("clear {:?}", root))?;
+    }
+    std::fs::create_dir_all(&root).with_context(|| format!("create {:?}", root))?;
+    let staged = StagedTree {
+        tree,
+        workdir: root.join(prefix.trim()),
+        root,
+    };
+    // The trailing slash makes the prefix a directory
+    let dest = format!("--prefix={}/", staged.root.display());
+    workspace::git(&["-C", top.trim(), "checkout-index", "--all", &dest])?;
+    Ok(staged)
+}
+
+impl Drop for StagedTree {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_holds_staged_content_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path();
+        let path = repo.display().to_string();
+        let git = |args: &[&str]| workspace::git(&[&["-C", path.as_str()], args].concat());
+        git(&["init", "--quiet"]).unwrap();
+        std::fs::write(repo.join("lib.rs"), "staged\n").unwrap();
+        git(&["add", "lib.rs"]).unwrap();
+        std::fs::write(repo.join("lib.rs"), "unstaged\n").unwrap();
+        std::fs::write(repo.join("new.rs"), "untracked\n").unwrap();
+
+        let staged = export_from(repo).unwrap();
+        let content = std::fs::read_to_string(staged.workdir.join("lib.rs")).unwrap();
+        let untracked = staged.workdir.join("new.rs").exists();
+        let tree = git(&["write-tree"]).unwrap();
+        let exported = staged.tree.clone();
+        drop(staged);
+        assert_eq!(content, "staged\n");
+        assert!(!untracked);
+        assert_eq!(exported, tree.trim());
+    }
+}
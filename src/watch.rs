@@ -0,0 +1,185 @@
+use crate::config::{self, Config};
+use crate::lock::{self, Holder};
+use crate::metrics;
+use crate::runner::{self, RunOptions};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the project tree is rescanned
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Modification time and size of every watched file
+type Snapshot = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// Run the pipeline, then again after every settled change, forever.
+///
+/// A change that arrives mid-run kills the running stages and starts over;
+/// runs that complete are appended to history like `dwf run`. `opts.abort`
+/// is the Ctrl-C flag: it stops the current run, records it and returns.
+/// Each run holds the project's run lock, and gives way to a `dwf run`
+/// that starts waiting for it.
+pub fn watch(
+    mut cfg: Config,
+    mode: &str,
+    opts: RunOptions,
+    save: bool,
+    debounce: Duration,
+) -> Result<()> {
+    println!("Watching for changes ({} mode, Ctrl-C to stop)", mode);
+    let interrupt = opts.abort.clone().unwrap_or_default();
+    let interrupted = || interrupt.load(Ordering::SeqCst);
+    let mut snap = snapshot(&cfg);
+
+    loop {
+        let Some(run_lock) = lock::acquire(&cfg, Holder::Watch, true, Some(&*interrupt))? else {
+            return Ok(());
+        };
+        let abort = Arc::new(AtomicBool::new(false));
+        let run_opts = RunOptions {
+            abort: Some(abort.clone()),
+            ..opts.clone()
+        };
+
+        let (result, changed, preempted) = std::thread::scope(|scope| {
+            let handle = scope.spawn(|| runner::run_pipeline(&cfg, mode, &run_opts));
+            let (mut changed, mut preempted) = (false, false);
+            while !handle.is_finished() {
+                std::thread::sleep(POLL_INTERVAL);
+                if interrupted() {
+                    abort.store(true, Ordering::SeqCst);
+                } else if changed || preempted {
+                    // Already stopping
+                } else if snapshot(&cfg) != snap {
+                    changed = true;
+                    abort.store(true, Ordering::SeqCst);
+                } else if lock::preempt_requested(&cfg) {
+                    preempted = true;
+                    abort.store(true, Ordering::SeqCst);
+                }
+            }
+            (handle.join(), changed, preempted)
+        });
+        drop(run_lock);
+        let result = result.map_err(|_| anyhow!("pipeline thread panicked"))?;
+
+        if interrupted() {
+            // The runner marked the run aborted if it was cut short
+            if let Ok(run) = result {
+                runner::print_run_summary(&run, opts.stream);
+                if save {
+                    metrics::append_run(&run)?;
+                }
+            }
+            return Ok(());
+        }
+        if preempted {
+            println!("\nAnother dwf run is waiting; restarting after it.");
+        } else if changed {
+            println!("\nChange detected mid-run; restarting.");
+        } else {
+            match result {
+                Ok(run) => {
+                    runner::print_run_summary(&run, opts.stream);
+                    if save {
+                        metrics::append_run(&run)?;
+                    }
+                }
+                Err(e) => eprintln!("dwf: {:#}", e),
+            }
+            println!("\nWaiting for changes…");
+            if !wait_for_change(&cfg, &snap, &interrupted) {
+                return Ok(());
+            }
+        }
+        // Ctrl-C while waiting for quiet must not start another run
+        let Some(settled) = settle(&cfg, debounce, &interrupted) else {
+            return Ok(());
+        };
+        snap = settled;
+
+        // Pick up dwf.toml edits between runs
+        match config::load_config() {
+            Ok(c) => cfg = c,
+            Err(e) => eprintln!("dwf: keeping previous config: {:#}", e),
+        }
+    }
+}
+
+/// False if interrupted before anything changed.
+fn wait_for_change(cfg: &Config, snap: &Snapshot, interrupted: &dyn Fn() -> bool) -> bool {
+    while snapshot(cfg) == *snap {
+        if interrupted() {
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    true
+}
+
+/// Wait until the tree stops changing for `debounce`, and return that state.
+/// None if interrupted first.
+fn settle(cfg: &Config, debounce: Duration, interrupted: &dyn Fn() -> bool) -> Option<Snapshot> {
+    let mut current = snapshot(cfg);
+    loop {
+        let until = Instant::now() + debounce.max(POLL_INTERVAL);
+        loop {
+            if interrupted() {
+                return None;
+            }
+            let now = Instant::now();
+            if now >= until {
+                break;
+            }
+            std::thread::sleep((until - now).min(POLL_INTERVAL));
+        }
+        let next = snapshot(cfg);
+        if next == current {
+            return Some(next);
+        }
+        current = next;
+    }
+}
+
+/// Files under the current directory, honoring .gitignore and skipping
+/// hidden files, `target/` and the dwf storage dir.
+fn snapshot(cfg: &Config) -> Snapshot {
+    let (storage, _file) = config::storage_paths(cfg);
+    let walker = ignore::WalkBuilder::new(".")
+        .require_git(false)
+        .filter_entry(move |e| !is_excluded(e.path(), &storage))
+        .build();
+
+    let mut snap = Snapshot::new();
+    for entry in walker.flatten() {
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        if let Ok(meta) = entry.metadata() {
+            snap.insert(entry.into_path(), (meta.modified().ok(), meta.len()));
+        }
+    }
+    snap
+}
+
+fn is_excluded(path: &Path, storage: &Path) -> bool {
+    let rel = path.strip_prefix(".").unwrap_or(path);
+    rel.starts_with("target") || rel.starts_with(storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excludes_target_and_storage() {
+        let storage = Path::new(".dwf");
+        assert!(is_excluded(Path::new("./target/debug/dwf"), storage));
+        assert!(is_excluded(Path::new("./.dwf/history.jsonl"), storage));
+        assert!(!is_excluded(Path::new("./src/main.rs"), storage));
+        assert!(!is_excluded(Path::new("./targets.md"), storage));
+    }
+}
@@ -0,0 +1,395 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Cargo subcommands that accept `-p <package>` (`nextest` meaning `nextest run`)
+const PACKAGE_SUBCOMMANDS: &[&str] = &["build", "check", "clippy", "test", "nextest"];
+
+/// Files outside any member that still affect every crate
+const GLOBAL_FILES: &[&str] = &[
+    "Cargo.toml",
+    "Cargo.lock",
+    "rust-toolchain",
+    "rust-toolchain.toml",
+    ".cargo",
+];
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    /// Absolute directory containing the member's Cargo.toml
+    pub dir: PathBuf,
+    /// Other workspace members this one depends on
+    pub deps: Vec<String>,
+    /// Features the package declares (optional dependencies included), without `default`
+    pub features: Vec<String>,
+}
+
+/// The cargo subcommand in `argv` (skipping a `+toolchain`), if it runs cargo.
+pub fn cargo_subcommand(argv: &[String]) -> Option<&str> {
+    if argv.first().map(String::as_str) != Some("cargo") {
+        return None;
+    }
+    argv[1..]
+        .iter()
+        .find(|a| !a.starts_with('+'))
+        .map(String::as_str)
+}
+
+pub fn accepts_package(argv: &[String]) -> bool {
+    cargo_subcommand(argv).is_some_and(|s| PACKAGE_SUBCOMMANDS.contains(&s))
+}
+
+/// `argv` limited to one package: `-p <pkg>` after the subcommand, without
+/// `--workspace`/`--all`.
+pub fn with_package(argv: &[String], package: &str) -> Vec<String> {
+    let mut out: Vec<String> = argv
+        .iter()
+        .filter(|a| *a != "--workspace" && *a != "--all")
+        .cloned()
+        .collect();
+    let sub = out
+        .iter()
+        .skip(1)
+        .position(|a| !a.starts_with('+'))
+        .map(|i| i + 1)
+        .unwrap_or(out.len() - 1);
+    // `cargo nextest run`: the package flag belongs to `run`
+    let sub = if out[sub] == "nextest" && out.get(sub + 1).is_some_and(|a| a == "run") {
+        sub + 1
+    } else {
+        sub
+    };
+    out.insert(sub + 1, "-p".to_string());
+    out.insert(sub + 2, package.to_string());
+    out
+}
+
+/// Workspace root and members, from `cargo metadata`.
+pub fn load_members() -> Result<(PathBuf, Vec<Member>)> {
+    parse_metadata(&metadata()?)
+}
+
+/// The build directory cargo uses for this workspace.
+pub fn target_dir() -> Result<PathBuf> {
+    metadata()?
+        .get("target_directory")
+        .and_then(Value::as_str)
+        .map(PathBuf::from)
+        .context("cargo metadata: missing target_directory")
+}
+
+fn metadata() -> Result<Value> {
+    let out = Command::new("cargo")
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .output()
+        .context("run cargo metadata")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    serde_json::from_slice(&out.stdout).context("parse cargo metadata")
+}
+
+fn parse_metadata(v: &Value) -> Result<(PathBuf, Vec<Member>)> {
+    let root = v
+        .get("workspace_root")
+        .and_then(Value::as_str)
+        .map(PathBuf::from)
+        .context("cargo metadata: missing workspace_root")?;
+    let packages = v
+        .get("packages")
+        .and_then(Value::as_array)
+        .context("cargo metadata: missing packages")?;
+
+    let names: Vec<&str> = packages
+        .iter()
+        .filter_map(|p| p.get("name").and_then(Value::as_str))
+        .collect();
+
+    let mut members = Vec::new();
+    for p in packages {
+        let name = p.get("name").and_then(Value::as_str).unwrap_or_default();
+        let manifest = p
+            .get("manifest_path")
+            .and_then(Value::as_str)
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        let deps = p
+            .get("dependencies")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|d| d.get("name").and_then(Value::as_str))
+            .filter(|d| names.contains(d) && *d != name)
+            .map(|d| d.to_string())
+            .collect();
+        let features = p
+            .get("features")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flat_map(|f| f.keys())
+            .filter(|f| *f != "default")
+            .cloned()
+            .collect();
+        members.push(Member {
+            name: name.to_string(),
+            dir: manifest.parent().map(Path::to_path_buf).unwrap_or_default(),
+            deps,
+            features,
+        });
+    }
+    Ok((root, members))
+}
+
+/// Absolute paths changed between the merge base with `base` and the working
+/// tree, plus untracked files. With `staged`, between it and the index
+/// instead: what the next commit would change.
+pub fn changed_files(base: &str, staged: bool) -> Result<Vec<PathBuf>> {
+    changed_files_in(Path::new("."), base, staged)
+}
+
+fn changed_files_in(dir: &Path, base: &str, staged: bool) -> Result<Vec<PathBuf>> {
+    let dir = dir.display().to_string();
+    let git = |args: &[&str]| git(&[&["-C", dir.as_str()], args].concat());
+    let top = git(&["rev-parse", "--show-toplevel"])?;
+    let top = PathBuf::from(top.trim());
+    let merge_base = git(&["merge-base", base, "HEAD"])
+        .with_context(|| format!("find merge base with `{}`", base))?;
+
+    let (diff, untracked) = if staged {
+        (
+            git(&["diff", "--cached", "--name-only", merge_base.trim()])?,
+            String::new(),
+        )
+    } else {
+        (
+            git(&["diff", "--name-only", merge_base.trim()])?,
+            git(&["ls-files", "--others", "--exclude-standard", "--full-name"])?,
+        )
+    };
+    Ok(diff
+        .lines()
+        .chain(untracked.lines())
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| top.join(l.trim()))
+        .collect())
+}
+
+/// The commit checked out, as a full hash.
+pub fn head_commit() -> Result<String> {
+    Ok(git(&["rev-parse", "HEAD"])?.trim().to_string())
+}
+
+/// Stdout of a git command that must succeed.
+pub fn git(args: &[&str]) -> Result<String> {
+    let out = Command::new("git")
+        .args(args)
+        .output()
+        .with_context(|| format!("run git {}", args.join(" ")))?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+/// Members touched by `changed`, plus everything that depends on them, in
+/// workspace order.
+pub fn affected(root: &Path, members: &[Member], changed: &[PathBuf]) -> Vec<String> {
+    let mut hit = vec![false; members.len()];
+    for path in changed {
+        if path.starts_with(root.join("target")) {
+            continue;
+        }
+        if let Ok(rel) = path.strip_prefix(root) {
+            if GLOBAL_FILES.iter().any(|g| rel.starts_with(g)) {
+                return members.iter().map(|m| m.name.clone()).collect();
+            }
+        }
+        // Nested crates: the deepest member directory owns the file
+        let owner = members
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| path.starts_with(&m.dir))
+            .max_by_key(|(_, m)| m.dir.components().count());
+        if let Some((i, _)) = owner {
+            hit[i] = true;
+        }
+    }
+
+    // Reverse dependencies, until nothing new is added
+    let mut grew = true;
+    while grew {
+        grew = false;
+        for i in 0..members.len() {
+            if hit[i] {
+                continue;
+            }
+            let depends_on_hit = members[i]
+                .deps
+                .iter()
+                .any(|d| members.iter().zip(&hit).any(|(m, &h)| h && &m.name == d));
+            if depends_on_hit {
+                hit[i] = true;
+                grew = true;
+            }
+        }
+    }
+
+    members
+        .iter()
+        .zip(&hit)
+        .filter(|(_, &h)| h)
+        .map(|(m, _)| m.name.clone())
+        .collect()
+}
+
+/// Packages that need checking: changed since `base` (in the index, with
+/// `staged`), plus their dependents.
+pub fn changed_packages(base: &str, staged: bool) -> Result<Vec<String>> {
+    let (root, members) = load_members()?;
+    let changed = changed_files(base, staged)?;
+    Ok(affected(&root, &members, &changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(name: &str, dir: &str, deps: &[&str]) -> Member {
+        Member {
+            name: name.to_string(),
+            dir: PathBuf::from(dir),
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            features: Vec::new(),
+        }
+    }
+
+    fn argv(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn changed_crate_pulls_in_dependents() {
+        let root = Path::new("/ws");
+        let members = vec![
+            member("core", "/ws/core", &[]),
+            member("api", "/ws/api", &["core"]),
+            member("cli", "/ws/cli", &["api"]),
+            member("docs", "/ws/docs", &[]),
+        ];
+
+        let got = affected(root, &members, &[PathBuf::from("/ws/core/src/lib.rs")]);
+        assert_eq!(got, ["core", "api", "cli"]);
+
+        let got = affected(root, &members, &[PathBuf::from("/ws/cli/src/main.rs")]);
+        assert_eq!(got, ["cli"]);
+
+        let got = affected(root, &members, &[PathBuf::from("/ws/README.md")]);
+        assert!(got.is_empty());
+
+        let got = affected(root, &members, &[PathBuf::from("/ws/Cargo.lock")]);
+        assert_eq!(got.len(), 4);
+    }
+
+    #[test]
+    fn nested_member_owns_its_files() {
+        let root = Path::new("/ws");
+        let members = vec![
+            member("app", "/ws", &[]),
+            member("sub", "/ws/crates/sub", &[]),
+        ];
+        let got = affected(
+            root,
+            &members,
+            &[PathBuf::from("/ws/crates/sub/src/lib.rs")],
+        );
+        assert_eq!(got, ["sub"]);
+    }
+
+    #[test]
+    fn package_flag_follows_subcommand() {
+        let a = with_package(
+            &argv(&["cargo", "clippy", "--workspace", "--", "-D", "warnings"]),
+            "core",
+        );
+        assert_eq!(
+            a,
+            argv(&["cargo", "clippy", "-p", "core", "--", "-D", "warnings"])
+        );
+        let a = with_package(&argv(&["cargo", "+nightly", "test", "-q"]), "api");
+        assert_eq!(a, argv(&["cargo", "+nightly", "test", "-p", "api", "-q"]));
+        assert!(!accepts_package(&argv(&["cargo", "fmt", "--check"])));
+        let a = with_package(
+            &argv(&["cargo", "nextest", "run", "--profile", "x"]),
+            "core",
+        );
+        assert_eq!(
+            a,
+            argv(&["cargo", "nextest", "run", "-p", "core", "--profile", "x"])
+        );
+    }
+
+    #[test]
+    fn parses_metadata_members() {
+        let v: Value = serde_json::from_str(
+            r#"{"workspace_root":"/ws","packages":[
+                {"name":"core","manifest_path":"/ws/core/Cargo.toml","dependencies":[{"name":"serde"}],
+                 "features":{"default":["std"],"std":[],"serde":["dep:serde"]}},
+                {"name":"api","manifest_path":"/ws/api/Cargo.toml","dependencies":[{"name":"core"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let (root, members) = parse_metadata(&v).unwrap();
+        assert_eq!(root, PathBuf::from("/ws"));
+        assert!(members[0].deps.is_empty());
+        assert_eq!(members[0].features, ["serde", "std"]);
+        assert!(members[1].features.is_empty());
+        assert_eq!(members[1].deps, ["core"]);
+        assert_eq!(members[1].dir, PathBuf::from("/ws/api"));
+    }
+
+    #[test]
+    fn staged_changes_come_from_the_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path();
+        let path = repo.display().to_string();
+        let git = |args: &[&str]| git(&[&["-C", path.as_str()], args].concat()).unwrap();
+        git(&["init", "--quiet"]);
+        std::fs::write(repo.join("a.rs"), "one\n").unwrap();
+        git(&["add", "a.rs"]);
+        git(&[
+            "-c",
+            "user.name=dwf",
+            "-c",
+            "user.email=dwf@example.com",
+            "commit",
+            "--quiet",
+            "-m",
+            "init",
+        ]);
+        std::fs::write(repo.join("a.rs"), "two\n").unwrap();
+        std::fs::write(repo.join("b.rs"), "staged\n").unwrap();
+        git(&["add", "b.rs"]);
+        std::fs::write(repo.join("c.rs"), "untracked\n").unwrap();
+
+        let names = |staged: bool| -> Vec<String> {
+            let mut names: Vec<String> = changed_files_in(repo, "HEAD", staged)
+                .unwrap()
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(false), ["a.rs", "b.rs", "c.rs"]);
+        assert_eq!(names(true), ["b.rs"]);
+    }
+}